@@ -4,9 +4,9 @@
 use anyhow::{Error, Result};
 use futures::stream::Stream;
 use futures::StreamExt;
-use gcp_auth;
 use gcs_rs::cli::parse_args;
 use gcs_rs::ops::gcs_bucket::RemoteStorage;
+use gcs_rs::ops::types::ListingMode;
 use std::num::NonZero;
 use std::pin::pin;
 use std::sync::Arc;
@@ -29,6 +29,14 @@ async fn main() -> Result<()> {
         bucket_name: "https://storage.googleapis.com/storage/v1/b/acrelab-production-us1c-transfer"
             .to_string(),
         prefix_in_bucket: None,
+        session_store: None,
+        upload_md5: false,
+        timeout: gcs_rs::ops::gcs_bucket::DEFAULT_TIMEOUT,
+        idle_timeout: gcs_rs::ops::gcs_bucket::DEFAULT_IDLE_TIMEOUT,
+        download_timeout: None,
+        upload_timeout: None,
+        delete_concurrency: gcs_rs::ops::gcs_bucket::DEFAULT_DELETE_CONCURRENCY,
+        max_keys_per_list_response: None,
     };
 
     // --- Bearer Token: ---
@@ -51,23 +59,29 @@ async fn main() -> Result<()> {
     let fs_size = usize::try_from(source_file.metadata().await?.len())?;
     let gcs_uri = "https://storage.googleapis.com/upload/storage/v1/b/acrelab-production-us1c-transfer/o?uploadType=media&name=nullbytes";
     let reader = tokio_util::io::ReaderStream::with_capacity(source_file, BUFFER_SIZE);
-    gcs.upload(reader, fs_size, gcs_uri).await?;
+    let cancel = CancellationToken::new();
+    gcs.upload(reader, Some(fs_size), gcs_uri, &cancel).await?;
 
     // --- Download: ---
     let cancel = CancellationToken::new();
     let remote_prefix = "bonk.geojson".to_string();
     use futures::stream::StreamExt;
-    let downloads = gcs.download_object(remote_prefix, &cancel).await?;
+    let downloads = gcs.download_object(remote_prefix, 3, &cancel).await?;
     let mut stream = std::pin::pin!(downloads.download_stream);
     while let Some(item) = stream.next().await {
         println!("{:?}", item);
     }
 
     // --- List: ---
-    // let cancel = CancellationToken::new();
+    let cancel = CancellationToken::new();
     let remote_prefix = "box/tiff/2023/TN".to_string();
     let max_keys: u32 = 100000;
-    let mut stream = pin!(gcs.list_streaming(Some(remote_prefix), NonZero::new(max_keys)));
+    let mut stream = pin!(gcs.list_streaming(
+        Some(remote_prefix),
+        ListingMode::NoDelimiter,
+        NonZero::new(max_keys),
+        &cancel
+    ));
     // Return some iterator
     let mut combined = stream.next().await.expect("At least one item required")?;
     while let Some(list) = stream.next().await {
@@ -1,2 +1,11 @@
+pub mod batch;
+pub mod checksum;
 pub mod gcs_bucket;
+pub mod multipart;
+pub mod reader;
+pub mod resumable;
+pub mod session_store;
+pub mod sharded_list;
+pub mod support;
+pub mod time_travel;
 pub mod types;
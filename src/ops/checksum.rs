@@ -0,0 +1,198 @@
+use crate::ops::types::{DownloadError, GCSObject};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream::Stream;
+use futures_util::StreamExt;
+use md5::{Digest, Md5};
+use std::sync::{Arc, Mutex};
+
+/// CRC32C, and MD5 when it was asked for, of a whole object.
+///
+/// GCS reports both base64-encoded in the object resource (`crc32c`, `md5Hash`), the CRC32C as
+/// four big-endian bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksums {
+    pub crc32c: u32,
+    pub md5: Option<[u8; 16]>,
+}
+
+impl Checksums {
+    pub fn crc32c_base64(&self) -> String {
+        STANDARD.encode(self.crc32c.to_be_bytes())
+    }
+
+    pub fn md5_base64(&self) -> Option<String> {
+        self.md5.map(|md5| STANDARD.encode(md5))
+    }
+
+    /// Value for the `x-goog-hash` header, e.g. `crc32c=n03x6A==,md5=Ojk9c3dhfxgoKVVHYwFbHQ==`.
+    pub fn x_goog_hash(&self) -> String {
+        match self.md5_base64() {
+            Some(md5) => format!("crc32c={},md5={}", self.crc32c_base64(), md5),
+            None => format!("crc32c={}", self.crc32c_base64()),
+        }
+    }
+
+    /// Compares against what GCS says it stored. MD5 is only compared when both sides have it:
+    /// composite objects have no `md5Hash`.
+    pub fn verify(&self, object: &GCSObject) -> Result<(), String> {
+        let crc32c = self.crc32c_base64();
+        if object.crc32c.as_ref() != Some(&crc32c) {
+            return Err(format!(
+                "crc32c of {} is {} locally but {} in GCS",
+                object.name,
+                crc32c,
+                object.crc32c.as_deref().unwrap_or("missing")
+            ));
+        }
+        if let (Some(md5), Some(remote)) = (self.md5_base64(), &object.md5_hash) {
+            if &md5 != remote {
+                return Err(format!(
+                    "md5 of {} is {} locally but {} in GCS",
+                    object.name, md5, remote
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Running CRC32C (and optionally MD5) over bytes as they go by.
+#[derive(Clone, Default)]
+pub struct Hasher {
+    crc32c: u32,
+    md5: Option<Md5>,
+}
+
+impl Hasher {
+    pub fn new(with_md5: bool) -> Self {
+        Self {
+            crc32c: 0,
+            md5: with_md5.then(Md5::new),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.crc32c = crc32c::crc32c_append(self.crc32c, bytes);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(bytes);
+        }
+    }
+
+    pub fn checksums(&self) -> Checksums {
+        Checksums {
+            crc32c: self.crc32c,
+            md5: self.md5.clone().map(|md5| md5.finalize().into()),
+        }
+    }
+}
+
+/// Passes `byte_stream` through, feeding every chunk to `hasher`. Read `hasher` once the stream
+/// has been drained.
+pub fn hashing_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    hasher: Arc<Mutex<Hasher>>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    byte_stream.map(move |item| {
+        if let Ok(bytes) = &item {
+            hasher.lock().unwrap().update(bytes);
+        }
+        item
+    })
+}
+
+/// Passes a full-object download through, hashing it, and ends with an error wrapping
+/// [`DownloadError::Fatal`] instead of `None` if it doesn't hash to the CRC32C of `object`.
+///
+/// Only CRC32C is checked: every object has one, composite objects included.
+pub fn verified_download_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    object: &GCSObject,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    let name = object.name.clone();
+    let expected = object.crc32c.clone();
+
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        let mut hasher = Hasher::new(false);
+        while let Some(item) = byte_stream.next().await {
+            if let Ok(bytes) = &item {
+                hasher.update(bytes);
+            }
+            let failed = item.is_err();
+            yield item;
+            if failed {
+                return;
+            }
+        }
+
+        let actual = hasher.checksums().crc32c_base64();
+        if expected.as_ref() != Some(&actual) {
+            yield Err(std::io::Error::other(DownloadError::Fatal(format!(
+                "crc32c of {} is {} in GCS but {} was downloaded",
+                name,
+                expected.as_deref().unwrap_or("missing"),
+                actual
+            ))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_gcs_encoding() {
+        // `gcloud storage hash` of a file holding "hello world"
+        let mut hasher = Hasher::new(true);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let checksums = hasher.checksums();
+
+        assert_eq!("yZRlqg==", checksums.crc32c_base64());
+        assert_eq!(
+            Some("XrY7u+Ae7tCTyyK7j1rNww==".to_string()),
+            checksums.md5_base64()
+        );
+        assert_eq!(
+            "crc32c=yZRlqg==,md5=XrY7u+Ae7tCTyyK7j1rNww==",
+            checksums.x_goog_hash()
+        );
+        assert_eq!(None, Hasher::new(false).checksums().md5);
+    }
+
+    #[tokio::test]
+    async fn corrupted_download_ends_in_fatal() {
+        let object: GCSObject = serde_json::from_str(
+            r#"{"name":"bonk.geojson","bucket":"b","generation":"1","metageneration":"1",
+                "contentType":"text/plain","storageClass":"STANDARD","size":"11",
+                "crc32c":"yZRlqg==","etag":"CAE=","timeCreated":"2024-01-01T00:00:00Z",
+                "timeStorageClassUpdated":"2024-01-01T00:00:00Z",
+                "timeFinalized":"2024-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+
+        let chunks = |body: &'static str| {
+            futures::stream::iter(vec![Ok(Bytes::from_static(body.as_bytes()))])
+        };
+
+        let good: Vec<_> = verified_download_stream(chunks("hello world"), &object)
+            .collect()
+            .await;
+        assert_eq!(1, good.len());
+        assert!(good[0].is_ok());
+
+        let bad: Vec<_> = verified_download_stream(chunks("hello w0rld"), &object)
+            .collect()
+            .await;
+        assert_eq!(2, bad.len());
+        let err = bad[1].as_ref().unwrap_err();
+        assert!(matches!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<DownloadError>()),
+            Some(DownloadError::Fatal(_))
+        ));
+    }
+}
@@ -1,6 +1,14 @@
 #![allow(dead_code)]
 #![allow(unused)]
 
+use crate::ops::batch::{Batch, BatchRequest, BatchResponse, MAX_BATCH_SIZE};
+use crate::ops::checksum::{hashing_stream, verified_download_stream, Checksums, Hasher};
+use crate::ops::session_store::SessionStore;
+use crate::ops::support::{
+    idle_timeout_stream, progress_stream, timeout_or_cancel, timeout_or_cancel_stream,
+    upload_timeout_or_cancel, BodyProgress,
+};
+use crate::ops::time_travel::RecoveryPlan;
 use crate::ops::types;
 use anyhow::{Error, Result};
 use azure_core::Etag;
@@ -14,252 +22,773 @@ use futures_util::StreamExt;
 use gcp_auth::{Token, TokenProvider};
 use http::Method;
 use http::StatusCode;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use reqwest::{header, Client};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::num::NonZeroU32;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::pin::{pin, Pin};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
 use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio_util::io::{StreamReader, SyncIoBridge};
 use tokio_util::sync::CancellationToken;
-use types::{DownloadError, Listing, ListingObject};
+use types::{
+    BatchStatusError, DeleteError, DeleteOutcome, DownloadError, Listing, ListingMode,
+    ListingObject, ListingVersion, TimeTravelError, TimeoutOrCancel, UploadError, VersionListing,
+};
 use url::Url;
 use uuid::Uuid;
 
-const SCOPES: &[&str] = &["https://www.googleapis.com/auth/cloud-platform"];
+pub(crate) const SCOPES: &[&str] = &["https://www.googleapis.com/auth/cloud-platform"];
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Times [`GCSBucket::delete_objects`] sends a sub-request again after a `429` or `5xx`.
+pub const DELETE_RETRIES: u32 = 5;
+pub const DEFAULT_DELETE_CONCURRENCY: NonZeroUsize = NonZeroUsize::new(8).unwrap();
 
 pub struct GCSBucket {
     pub token_provider: Arc<dyn TokenProvider>,
     pub bucket_name: String,
     pub prefix_in_bucket: Option<String>,
-    //max_keys_per_list_response: Option<i32>,
-    //pub timeout: std::time::Duration,
+    /// Where resumable upload sessions are recorded, see [`GCSBucket::resume_uploads`].
+    pub session_store: Option<SessionStore>,
+    /// Hash uploads with MD5 as well as CRC32C and check both against what GCS stored.
+    pub upload_md5: bool,
+    /// Largest page [`RemoteStorage::list_streaming`] asks for; GCS's own default is 1000.
+    pub max_keys_per_list_response: Option<NonZeroU32>,
+    /// How long a request may take before it fails with a timeout. For a download that is until
+    /// the response headers are in, for an upload from when its body is out until the response
+    /// is; a resumable upload gets it per chunk.
+    pub timeout: Duration,
+    /// How long a [`DownloadStream`] may go without producing bytes, or the body of an upload
+    /// without being sent on, before it fails with a timeout.
+    pub idle_timeout: Duration,
+    /// How long reading a whole [`DownloadStream`] may take, resumes included. `None`, for no
+    /// limit, suits large objects: a download that keeps making progress isn't cut off.
+    pub download_timeout: Option<Duration>,
+    /// How long [`GCSBucket::upload`] and [`GCSBucket::upload_multipart`] may take in all.
+    /// `None`, for no limit, suits large objects the same way.
+    pub upload_timeout: Option<Duration>,
+    /// Batch requests [`GCSBucket::delete_objects`] has in flight at once.
+    pub delete_concurrency: NonZeroUsize,
 }
 
 impl GCSBucket {
+    /// `bucket_name` is the JSON API URI of the bucket; media goes through the `/upload` variant
+    /// of it.
+    pub(crate) fn upload_uri(&self) -> String {
+        self.bucket_name
+            .replacen("/storage/v1/", "/upload/storage/v1/", 1)
+    }
+
+    /// Uploads `byte_stream` to `gcs_uri` in a single `POST`.
+    ///
+    /// With `fs_size` known, it is sent as `Content-Length` and the stream has to produce exactly
+    /// that many bytes, or the upload fails with [`UploadError::SizeMismatch`] before GCS
+    /// creates the object. Without it, the body goes out with `Transfer-Encoding: chunked`.
+    ///
+    /// The body is hashed on the way out and compared with the `crc32c`/`md5Hash` GCS reports,
+    /// see [`UploadError::ChecksumMismatch`].
+    ///
+    /// The upload fails with [`UploadError::Timeout`] if the body stalls for `idle_timeout`, the
+    /// response takes longer than `timeout` after it, or the whole takes `upload_timeout`.
     pub async fn upload(
         &self,
         byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
-        fs_size: usize,
+        fs_size: Option<usize>,
         gcs_uri: &str,
-    ) -> Result<()> {
-        // https://cloud.google.com/storage/docs/xml-api/reference-headers#chunked
+        cancel: &CancellationToken,
+    ) -> Result<types::GCSObject, UploadError> {
+        let deadline = self.upload_timeout.map(|t| Instant::now() + t);
+        let token = self
+            .token_provider
+            .token(SCOPES)
+            .await
+            .map_err(|e: gcp_auth::Error| UploadError::Other(e.into()))?;
+        let hasher = Arc::new(Mutex::new(Hasher::new(self.upload_md5)));
+        let progress = BodyProgress::new();
+        let byte_stream = hashing_stream(byte_stream, Arc::clone(&hasher));
+
         let mut headers = header::HeaderMap::new();
-        headers.insert(
-            header::TRANSFER_ENCODING,
-            header::HeaderValue::from_static("chunked"),
-        );
+        let mismatch = Arc::new(Mutex::new(None));
+        let body = match fs_size {
+            Some(fs_size) => {
+                headers.insert(header::CONTENT_LENGTH, header::HeaderValue::from(fs_size));
+                reqwest::Body::wrap_stream(progress_stream(
+                    sized_stream(byte_stream, fs_size as u64, Arc::clone(&mismatch)),
+                    Arc::clone(&progress),
+                ))
+            }
+            None => {
+                // https://cloud.google.com/storage/docs/xml-api/reference-headers#chunked
+                headers.insert(
+                    header::TRANSFER_ENCODING,
+                    header::HeaderValue::from_static("chunked"),
+                );
+                reqwest::Body::wrap_stream(progress_stream(byte_stream, Arc::clone(&progress)))
+            }
+        };
 
-        let res = Client::new()
+        let req = Client::new()
             .post(gcs_uri)
-            .body(reqwest::Body::wrap_stream(byte_stream))
+            .body(body)
             .headers(headers)
-            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str())
-            .send()
-            .await?;
+            .bearer_auth(token.as_str());
+        let res = self
+            .upload_timeout_or_cancel(req.send(), &progress, deadline, cancel)
+            .await?
+            .map_err(
+                |e: reqwest::Error| match (fs_size, *mismatch.lock().unwrap()) {
+                    (Some(expected), Some(actual)) => UploadError::SizeMismatch {
+                        expected: expected as u64,
+                        actual,
+                    },
+                    _ => UploadError::Transport(e.into()),
+                },
+            )?;
+
+        let object = timeout_or_cancel(
+            uploaded_object(res),
+            self.response_deadline(deadline),
+            cancel,
+        )
+        .await??;
+        verify_upload(object, &hasher)
+    }
 
-        Ok(())
+    /// [`upload_timeout_or_cancel`] with the bucket's timeouts.
+    async fn upload_timeout_or_cancel<T>(
+        &self,
+        send: impl std::future::Future<Output = T>,
+        progress: &Mutex<BodyProgress>,
+        deadline: Option<Instant>,
+        cancel: &CancellationToken,
+    ) -> Result<T, TimeoutOrCancel> {
+        upload_timeout_or_cancel(
+            send,
+            progress,
+            self.idle_timeout,
+            self.timeout,
+            deadline,
+            cancel,
+        )
+        .await
     }
 
-    pub async fn copy(&self, from: String, to: String, cancel: &CancellationToken) -> Result<()> {
-        let bucket: &str = "acrelab-production-us1c-transfer";
-        let copy_uri = self.bucket_name.clone()
-            + "/"
-            + bucket
-            + "/o/"
-            + &from
-            + "/copyTo/b/"
-            + bucket
-            + "/o/"
-            + &to;
-        let res = Client::new().post(copy_uri);
+    /// When reading a response that has just come in has to be done by: after the bucket's
+    /// timeout, or at `deadline` if that is sooner.
+    fn response_deadline(&self, deadline: Option<Instant>) -> Instant {
+        let timeout = Instant::now() + self.timeout;
+        deadline.map_or(timeout, |deadline| deadline.min(timeout))
+    }
 
-        Ok(())
+    /// Uploads `byte_stream` as `name` with `uploadType=multipart`: one `multipart/related` body
+    /// carrying the object resource as JSON, then the media. This is how `contentType`,
+    /// `cacheControl` and custom `metadata` get set in the same request, e.g. writing back the
+    /// [`Download::metadata`] of an object that was downloaded.
+    ///
+    /// When the checksums of the media are known up front, pass them as `expected`: they go in
+    /// the object resource and GCS refuses to create the object if the body doesn't match. The
+    /// body is hashed on the way out and checked against the response either way.
+    ///
+    /// Times out like [`GCSBucket::upload`].
+    #[allow(clippy::too_many_arguments)]
+    pub async fn upload_multipart(
+        &self,
+        byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+        name: &str,
+        content_type: &str,
+        cache_control: Option<&str>,
+        metadata: Option<StorageMetadata>,
+        expected: Option<Checksums>,
+        cancel: &CancellationToken,
+    ) -> Result<types::GCSObject, UploadError> {
+        let deadline = self.upload_timeout.map(|t| Instant::now() + t);
+        let token = self
+            .token_provider
+            .token(SCOPES)
+            .await
+            .map_err(|e: gcp_auth::Error| UploadError::Other(e.into()))?;
+        let resource = types::GCSObjectResource {
+            name: name.to_string(),
+            content_type: Some(content_type.to_string()),
+            cache_control: cache_control.map(str::to_string),
+            metadata: metadata.map(|m| m.0),
+            storage_class: None,
+            crc32c: expected.map(|c| c.crc32c_base64()),
+            md5_hash: expected.and_then(|c| c.md5_base64()),
+            ..Default::default()
+        };
+        let resource_json = serde_json::to_string(&resource)
+            .map_err(|e: serde_json::Error| UploadError::Other(e.into()))?;
+
+        // https://cloud.google.com/storage/docs/uploading-objects#uploading-an-object
+        let boundary = format!("gcs-rs-{}", Uuid::new_v4().simple());
+        let (head, tail) = multipart_related_frame(&boundary, &resource_json, content_type);
+        let hasher = Arc::new(Mutex::new(Hasher::new(self.upload_md5)));
+        let progress = BodyProgress::new();
+        let body = futures::stream::once(async move { Ok(Bytes::from(head)) })
+            .chain(hashing_stream(byte_stream, Arc::clone(&hasher)))
+            .chain(futures::stream::once(async move { Ok(Bytes::from(tail)) }));
+        let body = progress_stream(body, Arc::clone(&progress));
+
+        let req = Client::new()
+            .post(format!("{}/o?uploadType=multipart", self.upload_uri()))
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/related; boundary={}", boundary),
+            )
+            .body(reqwest::Body::wrap_stream(body))
+            .bearer_auth(token.as_str());
+        let res = self
+            .upload_timeout_or_cancel(req.send(), &progress, deadline, cancel)
+            .await?
+            .map_err(|e: reqwest::Error| UploadError::Transport(e.into()))?;
+
+        let object = timeout_or_cancel(
+            uploaded_object(res),
+            self.response_deadline(deadline),
+            cancel,
+        )
+        .await??;
+        verify_upload(object, &hasher)
     }
 
-    pub async fn delete_objects(&self, paths: &[&str], cancel: &CancellationToken) -> Result<()> {
-        let mut delete_objects = Vec::with_capacity(paths.len());
+    /// Copies `from` to `to` within the bucket. See [`GCSBucket::rewrite`].
+    pub async fn copy(&self, from: String, to: String, cancel: &CancellationToken) -> Result<()> {
+        self.rewrite(&from, &to, &CopyOpts::default(), |_| {}, cancel)
+            .await?;
+        Ok(())
+    }
 
-        let mut cancel = std::pin::pin!(cancel.cancelled());
+    /// Server-side copy of `from` to `to` with `objects.rewrite`. Large objects, and copies to
+    /// another location or storage class, take several calls: each answers with a
+    /// `rewriteToken` for the next, and `on_progress` hears how far GCS has got after every one.
+    ///
+    /// A destination object resource sent with the calls replaces the source's whole, so when
+    /// `opts` changes the metadata or storage class, the source's resource is fetched first and
+    /// the copy keeps the rest of it, pinned to that generation.
+    ///
+    /// Each call gets the bucket's timeout. A failed precondition comes back as an error
+    /// wrapping [`UploadError::PreconditionFailed`].
+    pub async fn rewrite(
+        &self,
+        from: &str,
+        to: &str,
+        opts: &CopyOpts,
+        on_progress: impl FnMut(CopyProgress),
+        cancel: &CancellationToken,
+    ) -> Result<types::GCSObject> {
+        let destination_bucket = match &opts.destination_bucket {
+            Some(bucket) => bucket.as_str(),
+            None => self.bucket()?,
+        };
+        let rewrite_uri = format!(
+            "{}/o/{}/rewriteTo/b/{}/o/{}",
+            self.bucket_name,
+            encode_key(from),
+            destination_bucket,
+            encode_key(to)
+        );
 
-        for path in paths {
-            let encoded_path: String =
-                url::form_urlencoded::byte_serialize(path.as_bytes()).collect();
-            delete_objects.push(encoded_path);
-            //delete_objects.push(match &self.prefix_in_bucket {
-            //    Some(prefix) => self.bucket_name.clone() + &prefix.clone() + "/" + path,
-            //    None => self.bucket_name.clone() + "/" + path,
-            //});
+        let mut opts = opts.clone();
+        let mut body = None;
+        if opts.rewrites_resource() {
+            let preconditions = DownloadOpts {
+                if_generation_match: opts.if_source_generation_match,
+                ..Default::default()
+            };
+            let source =
+                self.conditional_object_metadata(from, opts.source_generation, &preconditions);
+            let source = timeout_or_cancel(source, Instant::now() + self.timeout, cancel).await??;
+            if opts.source_generation.is_none() {
+                opts.source_generation =
+                    source.generation.as_deref().map(str::parse).transpose()?;
+            }
+            body = Some(serde_json::to_string(
+                &opts.destination_resource(to, &source),
+            )?);
         }
+        let query = opts.query();
+
+        rewrite_calls(
+            |rewrite_token| {
+                Box::pin(self.rewrite_call(
+                    &rewrite_uri,
+                    &query,
+                    rewrite_token,
+                    body.as_deref(),
+                    (from, to),
+                    cancel,
+                ))
+            },
+            on_progress,
+        )
+        .await
+    }
 
-        let mut form = reqwest::multipart::Form::new();
-        let bulk_uri = "https://storage.googleapis.com/batch/storage/v1";
-
-        let mut logger = HashMap::new();
-
-        for (index, path_to_delete) in delete_objects.iter().enumerate() {
-            logger.insert(index + 1, false);
-
-            let delete_req = format!(
-                "
-                DELETE /storage/v1/b/acrelab-production-us1c-transfer/o/{} HTTP/1.1\r\n\
-                Content-Type: application/json\r\n\
-                accept: application/json\r\n\
-                content-length: 0\r\n
-                ",
-                path_to_delete
-            )
-            .trim()
-            .to_string();
-
-            println!(
-                "Trying to delete: {} in bucket: {}",
-                path_to_delete, self.bucket_name
+    /// One `objects.rewrite` call, carrying on from `rewrite_token` if there is one.
+    async fn rewrite_call(
+        &self,
+        rewrite_uri: &str,
+        query: &str,
+        rewrite_token: Option<String>,
+        body: Option<&str>,
+        (from, to): (&str, &str),
+        cancel: &CancellationToken,
+    ) -> Result<types::GCSRewriteResponse> {
+        let deadline = Instant::now() + self.timeout;
+        let mut query = query.to_string();
+        if let Some(token) = &rewrite_token {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query = query + "rewriteToken=" + &encode_key(token);
+        }
+        let uri = format!("{}?{}", rewrite_uri, query);
+
+        let req = Client::new()
+            .post(uri)
+            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str());
+        let req = match body {
+            Some(body) => req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string()),
+            None => req.header(header::CONTENT_LENGTH, 0),
+        };
+        let res = timeout_or_cancel(req.send(), deadline, cancel).await??;
+        let status = res.status();
+        let res_body = timeout_or_cancel(res.text(), deadline, cancel).await??;
+        if !status.is_success() {
+            return Err(
+                anyhow::Error::from(UploadError::from_response(status, &res_body))
+                    .context(format!("rewrite of {} to {}", from, to)),
             );
+        }
+        Ok(serde_json::from_str(&res_body)?)
+    }
 
-            //println!("Delete request: \n{}", delete_req);
+    /// The bucket's name: `bucket_name` is its JSON API URI, ending in `/b/<bucket>`.
+    pub(crate) fn bucket(&self) -> Result<&str> {
+        self.bucket_name
+            .rsplit_once("/b/")
+            .map(|(_, bucket)| bucket)
+            .ok_or_else(|| anyhow::anyhow!("unexpected bucket URI {}", self.bucket_name))
+    }
 
-            let content_id = format!("<{}+{}>", Uuid::new_v4(), index + 1);
+    /// Deletes `paths` with batch requests of up to [`MAX_BATCH_SIZE`], `delete_concurrency` of
+    /// them at a time, returning what happened to each path in the same order. A path that
+    /// doesn't exist counts as deleted.
+    ///
+    /// Sub-requests that fail with `429` or `5xx`, or that the batch has no answer for, are sent
+    /// again in a batch of their own, up to [`DELETE_RETRIES`] times. So is a whole batch that
+    /// fails that way or gets no response; one rejected outright (e.g. `401`/`403`) isn't. The
+    /// paths that still couldn't be deleted come back in [`DeleteError::Failed`].
+    pub async fn delete_objects(
+        &self,
+        paths: &[&str],
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, DeleteOutcome)>, DeleteError> {
+        delete_in_batches(paths, self.delete_concurrency, cancel, |indices| {
+            Box::pin(self.delete_batch(paths, None, indices, cancel))
+        })
+        .await
+    }
 
-            let mut part_headers = header::HeaderMap::new();
-            part_headers.insert(
-                header::CONTENT_TYPE,
-                header::HeaderValue::from_static("application/http"),
-            );
-            part_headers.insert(
-                header::TRANSFER_ENCODING,
-                header::HeaderValue::from_static("binary"),
-            );
-            part_headers.insert(
-                header::HeaderName::from_static("content-id"),
-                header::HeaderValue::from_str(&content_id)?,
-            );
-            let part = reqwest::multipart::Part::text(delete_req).headers(part_headers);
+    /// Like [`GCSBucket::delete_objects`], but deletes each key only while its live generation
+    /// is still the one given with it (`ifGenerationMatch`). A key written since fails with
+    /// `412` and comes back in [`DeleteError::Failed`].
+    pub async fn delete_generations(
+        &self,
+        objects: &[(&str, u64)],
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, DeleteOutcome)>, DeleteError> {
+        let (paths, generations): (Vec<&str>, Vec<u64>) = objects.iter().copied().unzip();
+        delete_in_batches(&paths, self.delete_concurrency, cancel, |indices| {
+            Box::pin(self.delete_batch(&paths, Some(&generations), indices, cancel))
+        })
+        .await
+    }
 
-            form = form.part(format!("request-{}", index), part);
+    /// Sends one batch request deleting the `paths` at `indices`, if given only at the
+    /// `generations` at those indices, and returns the status of each sub-request in the same
+    /// order, or why there isn't one.
+    async fn delete_batch(
+        &self,
+        paths: &[&str],
+        generations: Option<&[u64]>,
+        indices: Vec<usize>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<std::result::Result<StatusCode, String>>> {
+        let bucket = self.bucket()?;
+        let mut batch = Batch::new();
+        for index in indices {
+            batch.push(match generations {
+                Some(generations) => {
+                    BatchRequest::delete_object_generation(bucket, paths[index], generations[index])
+                }
+                None => BatchRequest::delete_object(bucket, paths[index]),
+            });
         }
+        Ok(batch_statuses(self.send_batch(&batch, cancel).await?))
+    }
 
-        println!("{:?}", form.boundary());
+    /// [`RemoteStorage::list_streaming`], with the listing narrowed down by GCS according to
+    /// `opts`.
+    pub fn list_streaming_with(
+        &self,
+        prefix: Option<String>,
+        mode: ListingMode,
+        max_keys: Option<NonZeroU32>,
+        opts: &ListOpts,
+        cancel: &CancellationToken,
+    ) -> impl Stream<Item = Result<Listing, DownloadError>> + Send + '_ {
+        self.list_pages(prefix, mode, opts, false, max_keys, cancel)
+            .map(|page| page.map(|page| listing(&page)))
+    }
 
-        let mut headers = header::HeaderMap::new();
-        headers.insert(
-            header::CONTENT_TYPE,
-            header::HeaderValue::from_str(&format!(
-                "multipart/mixed; boundary={}",
-                form.boundary()
-            ))?,
-        );
+    /// Like [`GCSBucket::list_streaming_with`], but lists every generation of each object in a
+    /// bucket with versioning on: the live one and the noncurrent ones. `max_keys` counts
+    /// generations, not keys.
+    pub fn list_versions_streaming(
+        &self,
+        prefix: Option<String>,
+        mode: ListingMode,
+        max_keys: Option<NonZeroU32>,
+        opts: &ListOpts,
+        cancel: &CancellationToken,
+    ) -> impl Stream<Item = Result<VersionListing, DownloadError>> + Send + '_ {
+        self.list_pages(prefix, mode, opts, true, max_keys, cancel)
+            .map(|page| page.and_then(|page| version_listing(&page)))
+    }
 
-        let res = Client::new()
-            .post(bulk_uri)
-            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str())
-            .multipart(form)
-            .headers(headers)
-            .send()
-            .await?;
+    fn list_pages(
+        &self,
+        prefix: Option<String>,
+        mode: ListingMode,
+        opts: &ListOpts,
+        versions: bool,
+        max_keys: Option<NonZeroU32>,
+        cancel: &CancellationToken,
+    ) -> impl Stream<Item = Result<types::GCSListResponse, DownloadError>> + Send + '_ {
+        let cancel = cancel.clone();
+        list_pages(
+            prefix,
+            mode,
+            opts.clone(),
+            versions,
+            max_keys,
+            self.max_keys_per_list_response,
+            move |query| {
+                let cancel = cancel.clone();
+                async move {
+                    self.list_objects(format!("{}/o?{}", self.bucket_name, query), &cancel)
+                        .await
+                }
+            },
+        )
+    }
 
-        if !res.status().is_success() {
-            return Err(anyhow::anyhow!("stuff happened"));
+    /// Fetches one page of `objects.list`. The page is parsed on a blocking thread as the body
+    /// streams in, rather than from the whole body read into memory first.
+    pub async fn list_objects(
+        &self,
+        gcs_uri: String,
+        cancel: &CancellationToken,
+    ) -> Result<types::GCSListResponse> {
+        let deadline = Instant::now() + self.timeout;
+        let req = Client::new()
+            .get(gcs_uri)
+            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str());
+        let res = timeout_or_cancel(req.send(), deadline, cancel).await??;
+        let status = res.status();
+        if !status.is_success() {
+            let body = timeout_or_cancel(res.text(), deadline, cancel).await??;
+            return Err(anyhow::anyhow!("GCS list returned {}: {}", status, body));
         }
 
-        // I have to do this "owning" first
-        // https://users.rust-lang.org/t/creates-a-temporary-which-is-freed-while-still-in-use-again/29211/2
-        // -> else, i'm creating a heap object then returning a referene to it with `to_str` or
-        //   `get`, before it's tethered. Like I've built a home without a foundation. It gets
-        //   washed away into the sea...
-        let res_headers = res.headers().to_owned();
+        let body = Box::pin(timeout_or_cancel_stream(
+            res.bytes_stream().map_err(std::io::Error::other),
+            Some(deadline),
+            cancel.clone(),
+        ));
+        let reader = SyncIoBridge::new(StreamReader::new(body));
+        let parsed = tokio::task::spawn_blocking(move || {
+            serde_json::from_reader::<_, types::GCSListResponse>(std::io::BufReader::new(reader))
+        })
+        .await?;
+        parsed.map_err(|e| {
+            let e = std::io::Error::from(e);
+            match e.get_ref().and_then(|e| e.downcast_ref::<DownloadError>()) {
+                Some(DownloadError::Timeout) => TimeoutOrCancel::Timeout.into(),
+                Some(DownloadError::Cancelled) => TimeoutOrCancel::Cancel.into(),
+                _ => anyhow::Error::from(e).context("failed to parse a list response"),
+            }
+        })
+    }
 
-        let boundary = res_headers
-            .get(header::CONTENT_TYPE)
-            .unwrap()
-            .to_str()?
-            .split("=")
-            .last()
-            .unwrap();
+    // need a 'bucket', a 'key', and a bytes 'range'.
+    /// Downloads all of `key`, picking the stream up again up to `resume_attempts` times in a
+    /// row if it breaks off ([`DownloadOpts::resume_attempts`]). See [`GCSBucket::download`] for
+    /// conditions and byte ranges.
+    pub async fn download_object(
+        &self,
+        key: String,
+        resume_attempts: u32,
+        cancel: &CancellationToken,
+    ) -> Result<Download, DownloadError> {
+        let opts = DownloadOpts {
+            resume_attempts,
+            ..Default::default()
+        };
+        self.download(key, &opts, cancel).await
+    }
 
-        println!("{}", boundary);
-
-        println!("---");
-
-        let res_body = res.text().await?;
-
-        let parsed: HashMap<String, String> = res_body
-            .split(&format!("--{}", boundary))
-            // only keep these things, and do stuff to them on the way.
-            .filter_map(|c| {
-                // mutable because 'find_map' requires mutable self.
-                let mut lines = c.lines();
-
-                let id = lines
-                    // .find() is: look for this static pattern and give me Some(index) or None.
-                    // .find_map():
-                    //      1. do all this stuff
-                    //      2. return the first non-None result
-                    .find_map(|line| {
-                        line
-                            // may give me all Nones, else, Some(everything-after-prefix)
-                            .strip_prefix("Content-ID:")
-                            // takes the Option<> and returns a new Some(),
-                            // or stops at the None it gets.
-                            .and_then(|suf| suf.split('+').last())
-                            // return a new Some()
-                            .and_then(|suf| suf.split('>').next())
-                            // trim() and to_string() can't fail (don't return Option<>)
-                            // so might as well use it.
-                            // -> we could also return
-                            .map(|x| x.trim().to_string())
-                    });
-
-                let status_code = lines.find_map(|line| {
-                    line.strip_prefix("HTTP/1.1")
-                        .and_then(|x| x.split_whitespace().next())
-                        .map(|x| x.trim().to_string())
-                });
-
-                id.zip(status_code)
-            })
-            .collect();
+    /// Downloads bytes `start..end_exclusive` of `key`, or `start..` to the end of the object
+    /// when `end_exclusive` is `None`. [`Download::range`] says what was actually served, which
+    /// is shorter than asked for if the range runs past the end of the object.
+    ///
+    /// Only a read of the whole object gets its crc32c checked.
+    pub async fn download_byte_range(
+        &self,
+        key: String,
+        start: u64,
+        end_exclusive: Option<u64>,
+        cancel: &CancellationToken,
+    ) -> Result<Download, DownloadError> {
+        let opts = DownloadOpts {
+            byte_start: start,
+            byte_end: end_exclusive,
+            ..Default::default()
+        };
+        self.download(key, &opts, cancel).await
+    }
 
-        for (id, code) in parsed.iter() {
-            println!("content-id: {}: status_code: {}", id, code);
-            println!("---");
+    /// Downloads `key` as described by `opts`. The preconditions are checked on the request for
+    /// the object resource, so an unchanged object costs one small request and comes back as
+    /// [`DownloadError::Unmodified`]; a failed `ifGenerationMatch`/`ifMetagenerationMatch` comes
+    /// back as [`DownloadError::PreconditionFailed`].
+    ///
+    /// Past `timeout` or once `cancel` fires, this fails with [`DownloadError::Timeout`] or
+    /// [`DownloadError::Cancelled`]. The [`Download::download_stream`] fails the same way when it
+    /// stalls for `idle_timeout`, runs past `download_timeout` or `cancel` fires.
+    pub async fn download(
+        &self,
+        key: String,
+        opts: &DownloadOpts,
+        cancel: &CancellationToken,
+    ) -> Result<Download, DownloadError> {
+        if opts.byte_end.is_some_and(|end| end <= opts.byte_start) {
+            return Err(DownloadError::BadInput(anyhow::anyhow!(
+                "empty byte range {}..{:?}",
+                opts.byte_start,
+                opts.byte_end
+            )));
         }
 
-        Ok(())
+        let deadline = Instant::now() + self.timeout;
+        let resp = timeout_or_cancel(
+            self.conditional_object_metadata(&key, None, opts),
+            deadline,
+            cancel,
+        )
+        .await??;
+        self.download_generation_range(resp, &key, opts, deadline, cancel)
+            .await
     }
 
-    pub async fn list_objects(&self, gcs_uri: String) -> Result<types::GCSListResponse> {
-        let res = Client::new()
-            .get(gcs_uri)
-            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str())
-            .send()
-            .await?;
+    /// [`GCSBucket::download`] of the generation `resp` describes, without fetching the object
+    /// resource again.
+    pub(crate) async fn download_generation_range(
+        &self,
+        resp: types::GCSObject,
+        key: &str,
+        opts: &DownloadOpts,
+        deadline: Instant,
+        cancel: &CancellationToken,
+    ) -> Result<Download, DownloadError> {
+        let (start, end_exclusive) = (opts.byte_start, opts.byte_end);
+        let generation = resp.generation.clone().ok_or_else(|| {
+            DownloadError::Other(anyhow::anyhow!(
+                "object resource of {} had no generation",
+                key
+            ))
+        })?;
+        // Byte Stream request
+        // Pinned to the generation we just read, so the crc32c is for the bytes we get, and so
+        // a resumed stream can't pick up in a newer one.
+        let media = MediaRequest {
+            token_provider: Arc::clone(&self.token_provider),
+            uri: object_uri(
+                &self.bucket_name,
+                key,
+                &format!("alt=media&generation={}", generation),
+            ),
+            idle_timeout: self.idle_timeout,
+        };
+        let res = timeout_or_cancel(media.send(start, end_exclusive), deadline, cancel).await??;
+
+        let object_size = resp.size.as_deref().and_then(|s| s.parse::<u64>().ok());
+        let status = res.status();
+        let content_range = res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let byte_stream = media.body(res);
+
+        let (range, download_stream): (ByteRange, DownloadStream) = match status {
+            StatusCode::PARTIAL_CONTENT => {
+                let range = content_range
+                    .as_deref()
+                    .and_then(ByteRange::from_content_range)
+                    .ok_or_else(|| {
+                        DownloadError::Other(anyhow::anyhow!(
+                            "206 response without a usable Content-Range"
+                        ))
+                    })?;
+                let byte_stream = resuming_stream(
+                    byte_stream,
+                    media.resumer(),
+                    range.start,
+                    range.end,
+                    opts.resume_attempts,
+                );
+                if range.is_whole_object() {
+                    (
+                        range,
+                        Box::pin(verified_download_stream(byte_stream, &resp)),
+                    )
+                } else {
+                    (range, byte_stream)
+                }
+            }
+            // The whole object came back anyway: cut out what was asked for.
+            StatusCode::OK => {
+                let size = object_size.ok_or_else(|| {
+                    DownloadError::Other(anyhow::anyhow!("object resource had no size"))
+                })?;
+                let range = ByteRange {
+                    start: start.min(size),
+                    end: end_exclusive.map_or(size, |end| end.min(size)),
+                    total: Some(size),
+                };
+                let byte_stream = resuming_stream(
+                    byte_stream,
+                    media.resumer(),
+                    0,
+                    range.end,
+                    opts.resume_attempts,
+                );
+                if range.is_whole_object() {
+                    (
+                        range,
+                        Box::pin(verified_download_stream(byte_stream, &resp)),
+                    )
+                } else {
+                    (
+                        range,
+                        Box::pin(slice_stream(byte_stream, range.start, range.len())),
+                    )
+                }
+            }
+            // `bytes=0-` of an empty object.
+            StatusCode::RANGE_NOT_SATISFIABLE if start == 0 && object_size == Some(0) => (
+                ByteRange {
+                    start: 0,
+                    end: 0,
+                    total: Some(0),
+                },
+                Box::pin(futures::stream::empty()),
+            ),
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                return Err(DownloadError::RangeNotSatisfiable {
+                    object_size: content_range
+                        .as_deref()
+                        .and_then(|v| v.strip_prefix("bytes */"))
+                        .and_then(|v| v.parse().ok())
+                        .or(object_size),
+                })
+            }
+            StatusCode::NOT_FOUND => return Err(DownloadError::NotFound),
+            status => {
+                return Err(DownloadError::Other(anyhow::anyhow!(
+                    "GCS GET media returned {}",
+                    status
+                )))
+            }
+        };
+
+        let download_stream = Box::pin(timeout_or_cancel_stream(
+            download_stream,
+            self.download_timeout
+                .map(|timeout| Instant::now() + timeout),
+            cancel.clone(),
+        ));
+
+        let metadata = resp.metadata.map(StorageMetadata);
+
+        // How does "into()" really work?
+        let last_modified: SystemTime = resp
+            .updated
+            .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+            .map(|s| s.into())
+            .unwrap_or(SystemTime::now());
 
-        let body = res.text().await?;
-        let resp: types::GCSListResponse = serde_json::from_str(&body)?;
-        Ok(resp)
+        // But let data stream pass through
+        Ok(Download {
+            download_stream,
+            etag: resp.etag.unwrap_or_default().into(),
+            last_modified,
+            metadata,
+            range,
+        })
     }
 
-    // need a 'bucket', a 'key', and a bytes 'range'.
-    pub async fn download_object(
+    /// Fetches the object resource (`alt=json`) of `key`.
+    pub async fn object_metadata(
         &self,
-        key: String,
+        key: &str,
         cancel: &CancellationToken,
-    ) -> Result<Download, DownloadError> {
+    ) -> Result<types::GCSObject, DownloadError> {
+        let deadline = Instant::now() + self.timeout;
+        timeout_or_cancel(
+            self.conditional_object_metadata(key, None, &DownloadOpts::default()),
+            deadline,
+            cancel,
+        )
+        .await?
+    }
+
+    /// The object resource of `generation` of `key`, or of the live one.
+    async fn conditional_object_metadata(
+        &self,
+        key: &str,
+        generation: Option<u64>,
+        opts: &DownloadOpts,
+    ) -> Result<types::GCSObject, DownloadError> {
         // Serialize Metadata in initial request
-        let metadata_uri_mod = "alt=json";
-        let uri = format!(
-            "{}/o/{}?{}",
-            self.bucket_name,
-            key.replace("/", "%2F"),
-            metadata_uri_mod
-        );
-        let url_encoded: String = url::form_urlencoded::byte_serialize(uri.as_bytes()).collect();
+        let base = match generation {
+            Some(generation) => format!("alt=json&generation={}", generation),
+            None => "alt=json".to_string(),
+        };
+        let uri = object_uri(&self.bucket_name, key, &opts.query(&base));
+
+        let mut headers = header::HeaderMap::new();
+        if let Some(etag) = &opts.etag {
+            headers.insert(
+                header::IF_NONE_MATCH,
+                header::HeaderValue::from_str(etag.as_ref())
+                    .map_err(|e| DownloadError::BadInput(e.into()))?,
+            );
+        }
 
         let res = Client::new()
             .get(uri)
+            .headers(headers)
             .bearer_auth(
                 self.token_provider
                     .token(SCOPES)
@@ -274,6 +803,8 @@ impl GCSBucket {
         if !res.status().is_success() {
             match res.status() {
                 StatusCode::NOT_FOUND => return Err(DownloadError::NotFound),
+                StatusCode::NOT_MODIFIED => return Err(DownloadError::Unmodified),
+                StatusCode::PRECONDITION_FAILED => return Err(DownloadError::PreconditionFailed),
                 _ => {
                     return Err(DownloadError::Other(anyhow::anyhow!(
                         "GCS GET resposne contained no response body"
@@ -287,18 +818,64 @@ impl GCSBucket {
             .await
             .map_err(|e: reqwest::Error| DownloadError::Other(e.into()))?;
 
-        let resp: types::GCSObject = serde_json::from_str(&body)
-            .map_err(|e: serde_json::Error| DownloadError::Other(e.into()))?;
+        serde_json::from_str(&body).map_err(|e: serde_json::Error| DownloadError::Other(e.into()))
+    }
+}
 
-        // Byte Stream request
-        let stream_uri_mod = "alt=media";
+/// The `rewriteToken` loop of [`GCSBucket::rewrite`]: `call` makes one call, given the token
+/// from the one before, until GCS is done.
+async fn rewrite_calls<'a>(
+    mut call: impl FnMut(
+        Option<String>,
+    ) -> futures::future::BoxFuture<'a, Result<types::GCSRewriteResponse>>,
+    mut on_progress: impl FnMut(CopyProgress),
+) -> Result<types::GCSObject> {
+    let mut rewrite_token: Option<String> = None;
+    loop {
+        let resp = call(rewrite_token.take()).await?;
+        on_progress(CopyProgress {
+            bytes_rewritten: resp.total_bytes_rewritten.parse()?,
+            object_size: resp.object_size.parse()?,
+        });
+        if resp.done {
+            return resp
+                .resource
+                .ok_or_else(|| anyhow::anyhow!("finished rewrite had no resource"));
+        }
+        rewrite_token = Some(
+            resp.rewrite_token
+                .ok_or_else(|| anyhow::anyhow!("unfinished rewrite had no rewriteToken"))?,
+        );
+    }
+}
+
+/// Media requests for one generation of an object, made without borrowing the bucket so that a
+/// [`DownloadStream`] can make more of them.
+#[derive(Clone)]
+struct MediaRequest {
+    token_provider: Arc<dyn TokenProvider>,
+    uri: String,
+    idle_timeout: Duration,
+}
+
+impl MediaRequest {
+    async fn send(
+        &self,
+        start: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<reqwest::Response, DownloadError> {
         let mut headers = header::HeaderMap::new();
-        headers.insert(header::RANGE, header::HeaderValue::from_static("bytes=0-"));
-        let uri = format!("{}/o/{}?{}", self.bucket_name, key, stream_uri_mod);
-        let url_encoded: String = url::form_urlencoded::byte_serialize(uri.as_bytes()).collect();
+        let range = match end_exclusive {
+            Some(end) => format!("bytes={}-{}", start, end - 1),
+            None => format!("bytes={}-", start),
+        };
+        headers.insert(
+            header::RANGE,
+            header::HeaderValue::from_str(&range).map_err(|e| DownloadError::Other(e.into()))?,
+        );
 
-        let mut res = Client::new()
-            .get(uri)
+        Client::new()
+            .get(&self.uri)
             .headers(headers)
             .bearer_auth(
                 self.token_provider
@@ -309,44 +886,413 @@ impl GCSBucket {
             )
             .send()
             .await
-            .map_err(|e: reqwest::Error| DownloadError::Other(e.into()))?;
+            .map_err(|e: reqwest::Error| DownloadError::Other(e.into()))
+    }
 
-        if !res.status().is_success() {
-            match res.status() {
-                StatusCode::NOT_FOUND => return Err(DownloadError::NotFound),
-                _ => {
-                    return Err(DownloadError::Other(anyhow::anyhow!(
-                        "GCS GET resposne contained no response body"
-                    )))
-                }
+    fn body(&self, res: reqwest::Response) -> DownloadStream {
+        let byte_stream = res
+            .bytes_stream()
+            .map(|item| item.map_err(|e: reqwest::Error| std::io::Error::other(e)));
+        Box::pin(idle_timeout_stream(byte_stream, self.idle_timeout))
+    }
+
+    /// Bytes `offset..end` again, after a stream broke off at `offset`.
+    async fn resume(&self, offset: u64, end: u64) -> std::io::Result<DownloadStream> {
+        let send = tokio::time::timeout(self.idle_timeout, self.send(offset, Some(end)));
+        let res = match send.await {
+            Ok(res) => res.map_err(std::io::Error::other)?,
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    DownloadError::Timeout,
+                ))
             }
         };
 
-        //let object_output = tokio::select! {
-        //    res = get_object => res,
-        //    //_ = tokio::time::sleep(self.timeout) => return Err(DownloadError::Timeout),
-        //    _ = cancel.cancelled() => return Err(DownloadError::Cancelled),
-        //};
+        let status = res.status();
+        let range = res
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(ByteRange::from_content_range);
+        resumed_body(status, range, self.body(res), offset, end)
+    }
 
-        let metadata = resp.metadata.map(StorageMetadata);
+    /// [`MediaRequest::resume`] for [`resuming_stream`].
+    fn resumer(self) -> impl Fn(u64, u64) -> ResumeFuture + Send + Sync + 'static {
+        move |offset, end| {
+            let media = self.clone();
+            Box::pin(async move { media.resume(offset, end).await })
+        }
+    }
+}
 
-        // How does "into()" really work?
-        let last_modified: SystemTime = resp
-            .updated
-            .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
-            .map(|s| s.into())
-            .unwrap_or(SystemTime::now());
+/// What a [`resuming_stream`] gets back when it asks for the rest of the bytes.
+type ResumeFuture = futures::future::BoxFuture<'static, std::io::Result<DownloadStream>>;
+
+/// The body of a response to a request for bytes `offset..end`, checked to start at `offset`.
+/// A `200` with the whole object gets cut down to the range.
+fn resumed_body(
+    status: StatusCode,
+    content_range: Option<ByteRange>,
+    body: DownloadStream,
+    offset: u64,
+    end: u64,
+) -> std::io::Result<DownloadStream> {
+    match status {
+        StatusCode::PARTIAL_CONTENT => match content_range {
+            Some(range) if range.start == offset => Ok(body),
+            range => Err(std::io::Error::other(format!(
+                "asked to resume at byte {} but got {:?}",
+                offset, range
+            ))),
+        },
+        StatusCode::OK => Ok(Box::pin(slice_stream(body, offset, end - offset))),
+        status => Err(std::io::Error::other(format!(
+            "resuming download at byte {} returned {}",
+            offset, status
+        ))),
+    }
+}
 
-        // But let data stream pass through
-        Ok(Download {
-            download_stream: Box::pin(res.bytes_stream().map(|item| {
-                item.map_err(|e: reqwest::Error| std::io::Error::new(std::io::ErrorKind::Other, e))
-            })),
-            etag: resp.etag.into(),
-            last_modified,
-            metadata,
+/// Passes through `first`, which holds bytes `start..end`, and when it breaks off (an error, or
+/// ending early) calls `resume` for the rest from the last byte delivered. Gives up with the
+/// error after `attempts` tries in a row that deliver nothing; with 0, `first` is returned as it
+/// is.
+fn resuming_stream(
+    first: DownloadStream,
+    resume: impl Fn(u64, u64) -> ResumeFuture + Send + Sync + 'static,
+    start: u64,
+    end: u64,
+    attempts: u32,
+) -> DownloadStream {
+    if attempts == 0 {
+        return first;
+    }
+
+    Box::pin(async_stream::stream! {
+        let mut current = first;
+        let mut offset = start;
+        let mut failures = 0;
+        loop {
+            let mut err = match current.next().await {
+                Some(Ok(bytes)) => {
+                    offset += bytes.len() as u64;
+                    failures = 0;
+                    yield Ok(bytes);
+                    continue;
+                }
+                None if offset >= end => return,
+                None => std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("download ended at byte {} of {}", offset, end),
+                ),
+                Some(Err(e)) => e,
+            };
+
+            loop {
+                failures += 1;
+                if failures > attempts {
+                    yield Err(err);
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(250 * 2u64.pow(failures.min(6)))).await;
+                // On a task of its own: the token and request futures aren't `Sync`, and
+                // `DownloadStream` has to be. A resume abandoned by dropping the stream runs out
+                // on that task, bounded by the idle timeout.
+                let resumed = tokio::spawn(resume(offset, end))
+                    .await
+                    .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+                match resumed {
+                    Ok(stream) => {
+                        current = stream;
+                        break;
+                    }
+                    Err(e) => err = e,
+                }
+            }
+        }
+    })
+}
+
+/// The status of each response of a batch, in order, or why there isn't one.
+fn batch_statuses(
+    responses: Vec<Option<BatchResponse>>,
+) -> Vec<std::result::Result<StatusCode, String>> {
+    responses
+        .into_iter()
+        .map(|response| {
+            response
+                .map(|response| response.status)
+                .ok_or_else(|| "no response in the batch".to_string())
         })
+        .collect()
+}
+
+/// One batch of deletes sent by [`delete_in_batches`]: the status of each sub-request, in order.
+type DeleteBatch<'a> =
+    futures::future::BoxFuture<'a, Result<Vec<std::result::Result<StatusCode, String>>>>;
+
+/// [`GCSBucket::delete_objects`], with `send` sending one batch that deletes the `paths` at the
+/// indices it is given.
+async fn delete_in_batches<'a>(
+    paths: &[&str],
+    concurrency: NonZeroUsize,
+    cancel: &CancellationToken,
+    send: impl Fn(Vec<usize>) -> DeleteBatch<'a>,
+) -> Result<Vec<(String, DeleteOutcome)>, DeleteError> {
+    let chunks = (0..paths.len())
+        .step_by(MAX_BATCH_SIZE)
+        .map(|start| start..(start + MAX_BATCH_SIZE).min(paths.len()));
+    let mut batches = futures::stream::iter(chunks)
+        .map(|chunk| delete_chunk(paths, chunk, cancel, &send))
+        .buffered(concurrency.get());
+
+    let mut outcomes = Vec::with_capacity(paths.len());
+    let mut failed = Vec::new();
+    while let Some(batch) = batches.next().await {
+        let (batch_outcomes, batch_failed) = batch?;
+        outcomes.extend(batch_outcomes);
+        failed.extend(batch_failed);
+    }
+
+    if !failed.is_empty() {
+        return Err(DeleteError::Failed(failed));
     }
+    Ok(outcomes)
+}
+
+/// Deletes the `paths` in `chunk`, at most [`MAX_BATCH_SIZE`] of them, retrying as
+/// [`GCSBucket::delete_objects`] describes. Returns the outcomes of the paths that were deleted
+/// and the ones that weren't.
+async fn delete_chunk<'a>(
+    paths: &[&str],
+    chunk: std::ops::Range<usize>,
+    cancel: &CancellationToken,
+    send: &impl Fn(Vec<usize>) -> DeleteBatch<'a>,
+) -> Result<(Vec<(String, DeleteOutcome)>, Vec<types::DeleteFailure>), DeleteError> {
+    let mut outcomes = vec![None; chunk.len()];
+    let mut failed = Vec::new();
+    let mut pending: Vec<usize> = chunk.clone().collect();
+    let mut attempts = 0;
+
+    while !pending.is_empty() {
+        let statuses = match send(pending.clone()).await {
+            Ok(statuses) => statuses,
+            Err(e) if e.downcast_ref() == Some(&TimeoutOrCancel::Cancel) => {
+                return Err(DeleteError::Cancelled)
+            }
+            // The batch as a whole failed, so every sub-request in it did.
+            Err(e) if retry_batch(&e) => vec![Err(format!("{:#}", e)); pending.len()],
+            Err(e) => {
+                let status = e.downcast_ref().map(|BatchStatusError(status)| *status);
+                failed.extend(pending.iter().map(|index| types::DeleteFailure {
+                    key: paths[*index].to_string(),
+                    status,
+                    message: format!("{:#}", e),
+                }));
+                break;
+            }
+        };
+
+        let mut retry = Vec::new();
+        for (index, status) in pending.into_iter().zip(statuses) {
+            match status {
+                Ok(status) if status.is_success() => {
+                    outcomes[index - chunk.start] = Some(DeleteOutcome::Deleted)
+                }
+                Ok(StatusCode::NOT_FOUND) => {
+                    outcomes[index - chunk.start] = Some(DeleteOutcome::NotFound)
+                }
+                Ok(status) if retry_status(status) => {
+                    retry.push((index, Some(status), status.to_string()))
+                }
+                Ok(status) => failed.push(types::DeleteFailure {
+                    key: paths[index].to_string(),
+                    status: Some(status),
+                    message: status.to_string(),
+                }),
+                Err(message) => retry.push((index, None, message)),
+            }
+        }
+
+        attempts += 1;
+        if attempts > DELETE_RETRIES {
+            failed.extend(
+                retry
+                    .into_iter()
+                    .map(|(index, status, message)| types::DeleteFailure {
+                        key: paths[index].to_string(),
+                        status,
+                        message,
+                    }),
+            );
+            break;
+        }
+        pending = retry.into_iter().map(|(index, _, _)| index).collect();
+        if !pending.is_empty() {
+            let backoff = Duration::from_millis(500 * 2u64.pow(attempts.min(6)));
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = cancel.cancelled() => return Err(DeleteError::Cancelled),
+            }
+        }
+    }
+
+    let outcomes = paths[chunk]
+        .iter()
+        .zip(outcomes)
+        .filter_map(|(path, outcome)| Some((path.to_string(), outcome?)))
+        .collect();
+    Ok((outcomes, failed))
+}
+
+fn retry_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Whether a batch that failed as a whole with `error` is worth sending again: it was rate
+/// limited or hit a server error, or no response came back.
+fn retry_batch(error: &anyhow::Error) -> bool {
+    match error.downcast_ref() {
+        Some(BatchStatusError(status)) => retry_status(*status),
+        None => {
+            error.downcast_ref::<reqwest::Error>().is_some()
+                || error.downcast_ref() == Some(&TimeoutOrCancel::Timeout)
+        }
+    }
+}
+
+/// Everything but RFC 3986 unreserved characters gets percent-encoded in a path segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes an object name for use as one path segment: `/` becomes `%2F`, a space
+/// `%20` and `+` `%2B`.
+pub(crate) fn encode_key(key: &str) -> String {
+    utf8_percent_encode(key, PATH_SEGMENT).to_string()
+}
+
+/// The JSON API URI of object `key` in the bucket at `bucket_uri`, with `query`.
+fn object_uri(bucket_uri: &str, key: &str, query: &str) -> String {
+    format!("{}/o/{}?{}", bucket_uri, encode_key(key), query)
+}
+
+/// Skips `skip` bytes of `byte_stream` and ends it after `take` more.
+fn slice_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    skip: u64,
+    take: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        let mut skip = skip;
+        let mut take = take;
+        while take > 0 {
+            let Some(item) = byte_stream.next().await else {
+                break;
+            };
+            let mut bytes = match item {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            if skip >= bytes.len() as u64 {
+                skip -= bytes.len() as u64;
+                continue;
+            }
+            let mut bytes = bytes.split_off(skip as usize);
+            skip = 0;
+            bytes.truncate(take.min(bytes.len() as u64) as usize);
+            take -= bytes.len() as u64;
+            yield Ok(bytes);
+        }
+    }
+}
+
+/// Checks the status of an upload response and parses the object resource GCS sends back.
+async fn uploaded_object(res: reqwest::Response) -> Result<types::GCSObject, UploadError> {
+    let status = res.status();
+    let body = res
+        .text()
+        .await
+        .map_err(|e: reqwest::Error| UploadError::Transport(e.into()))?;
+
+    if !status.is_success() {
+        return Err(UploadError::from_response(status, &body));
+    }
+
+    serde_json::from_str(&body).map_err(|e: serde_json::Error| UploadError::Other(e.into()))
+}
+
+fn verify_upload(
+    object: types::GCSObject,
+    hasher: &Mutex<Hasher>,
+) -> Result<types::GCSObject, UploadError> {
+    hasher
+        .lock()
+        .unwrap()
+        .checksums()
+        .verify(&object)
+        .map_err(UploadError::ChecksumMismatch)?;
+    Ok(object)
+}
+
+/// Passes `byte_stream` through and fails it as soon as it turns out not to be `expected` bytes
+/// long: on the chunk that goes over, or at the end if it comes up short. The count it got to is
+/// left in `mismatch`, so the caller can tell this apart from the stream's own errors.
+fn sized_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    expected: u64,
+    mismatch: Arc<Mutex<Option<u64>>>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        let mut count = 0;
+        while let Some(item) = byte_stream.next().await {
+            let bytes = item?;
+            count += bytes.len() as u64;
+            if count > expected {
+                *mismatch.lock().unwrap() = Some(count);
+                yield Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("upload stream produced more than the {} bytes it was sized at", expected),
+                ));
+                return;
+            }
+            yield Ok(bytes);
+        }
+        if count < expected {
+            *mismatch.lock().unwrap() = Some(count);
+            yield Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("upload stream ended after {} of {} bytes", count, expected),
+            ));
+        }
+    }
+}
+
+/// Everything in a `multipart/related` upload body except the media itself: the JSON part, the
+/// headers of the media part, and the closing delimiter.
+fn multipart_related_frame(
+    boundary: &str,
+    resource_json: &str,
+    content_type: &str,
+) -> (String, String) {
+    let head = format!(
+        "--{boundary}\r\n\
+        Content-Type: application/json; charset=UTF-8\r\n\r\n\
+        {resource_json}\r\n\
+        --{boundary}\r\n\
+        Content-Type: {content_type}\r\n\r\n"
+    );
+    let tail = format!("\r\n--{boundary}--\r\n");
+    (head, tail)
 }
 
 struct GetObjectRequest {
@@ -373,12 +1319,177 @@ pub struct Download {
     pub etag: Etag,
     /// Extra key-value data, associated with the current remote file.
     pub metadata: Option<StorageMetadata>,
+    /// The part of the object `download_stream` holds.
+    pub range: ByteRange,
+}
+
+/// What to download with [`GCSBucket::download`], and under which conditions.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOpts {
+    /// Sent as `If-None-Match`: if the object still has this ETag, the download is
+    /// [`DownloadError::Unmodified`].
+    pub etag: Option<Etag>,
+    pub if_generation_match: Option<u64>,
+    /// The download is [`DownloadError::Unmodified`] if the object is still this generation.
+    pub if_generation_not_match: Option<u64>,
+    pub if_metageneration_match: Option<u64>,
+    pub byte_start: u64,
+    /// Exclusive; `None` reads to the end of the object.
+    pub byte_end: Option<u64>,
+    /// How many times in a row a [`DownloadStream`] that breaks off is picked up again, with a
+    /// range request from the last byte it delivered. 0 leaves it broken.
+    pub resume_attempts: u32,
+}
+
+impl DownloadOpts {
+    /// The query string for the object resource request: `base` plus the generation
+    /// preconditions that are set.
+    fn query(&self, base: &str) -> String {
+        let mut query = base.to_string();
+        for (param, value) in [
+            ("ifGenerationMatch", self.if_generation_match),
+            ("ifGenerationNotMatch", self.if_generation_not_match),
+            ("ifMetagenerationMatch", self.if_metageneration_match),
+        ] {
+            if let Some(value) = value {
+                query += &format!("&{}={}", param, value);
+            }
+        }
+        query
+    }
+}
+
+/// Where and how [`GCSBucket::rewrite`] copies an object.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOpts {
+    /// Copy into this bucket instead of the same one.
+    pub destination_bucket: Option<String>,
+    /// Custom metadata and storage class for the copy, in place of the source's. Everything
+    /// else in the object resource, e.g. `contentType` and `cacheControl`, is kept either way.
+    pub metadata: Option<StorageMetadata>,
+    pub storage_class: Option<String>,
+    /// Copy this generation of `from` rather than the live one; it may be noncurrent.
+    pub source_generation: Option<u64>,
+    pub if_source_generation_match: Option<u64>,
+    /// `Some(0)` copies only if the destination doesn't exist yet.
+    pub if_generation_match: Option<u64>,
+    /// Limits how much GCS copies per call, so progress is reported more often.
+    pub max_bytes_rewritten_per_call: Option<u64>,
+}
+
+impl CopyOpts {
+    fn query(&self) -> String {
+        [
+            ("sourceGeneration", self.source_generation),
+            ("ifSourceGenerationMatch", self.if_source_generation_match),
+            ("ifGenerationMatch", self.if_generation_match),
+            (
+                "maxBytesRewrittenPerCall",
+                self.max_bytes_rewritten_per_call,
+            ),
+        ]
+        .iter()
+        .filter_map(|(param, value)| value.map(|value| format!("{}={}", param, value)))
+        .collect::<Vec<_>>()
+        .join("&")
+    }
+
+    /// Whether the rewrite has to send a destination object resource.
+    fn rewrites_resource(&self) -> bool {
+        self.metadata.is_some() || self.storage_class.is_some()
+    }
+
+    /// The object resource `source` is copied to `name` with: its own, with the metadata and
+    /// storage class of `self` in place of its own if set.
+    fn destination_resource(
+        &self,
+        name: &str,
+        source: &types::GCSObject,
+    ) -> types::GCSObjectResource {
+        types::GCSObjectResource {
+            name: name.to_string(),
+            content_type: source.content_type.clone(),
+            cache_control: source.cache_control.clone(),
+            content_encoding: source.content_encoding.clone(),
+            content_disposition: source.content_disposition.clone(),
+            content_language: source.content_language.clone(),
+            metadata: match &self.metadata {
+                Some(metadata) => Some(metadata.0.clone()),
+                None => source.metadata.clone(),
+            },
+            storage_class: self
+                .storage_class
+                .clone()
+                .or_else(|| source.storage_class.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// How far a [`GCSBucket::rewrite`] has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyProgress {
+    pub bytes_rewritten: u64,
+    pub object_size: u64,
+}
+
+/// What [`GCSBucket::list_streaming_with`] has GCS filter a listing down to.
+#[derive(Debug, Clone, Default)]
+pub struct ListOpts {
+    /// Only names at or after this one, lexicographically.
+    pub start_offset: Option<String>,
+    /// Only names before this one.
+    pub end_offset: Option<String>,
+    /// Only names matching this glob, e.g. `**/*.tif`. See
+    /// <https://cloud.google.com/storage/docs/json_api/v1/objects/list#list-objects-and-prefixes-using-glob>.
+    pub match_glob: Option<String>,
+    /// With [`ListingMode::WithDelimiter`], also list objects whose names end in `/` as keys,
+    /// not just as prefixes.
+    pub include_trailing_delimiter: bool,
+}
+
+/// Bytes `start..end` of an object that is `total` bytes long, if GCS said.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+    pub total: Option<u64>,
+}
+
+impl ByteRange {
+    /// Parses a `Content-Range` response header, e.g. `bytes 0-99/1234` or `bytes 0-99/*`.
+    pub fn from_content_range(value: &str) -> Option<Self> {
+        let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+        let (first, last) = range.split_once('-')?;
+        let total = match total {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        Some(Self {
+            start: first.parse().ok()?,
+            end: last.parse::<u64>().ok()? + 1,
+            total,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn is_whole_object(&self) -> bool {
+        self.start == 0 && Some(self.end) == self.total
+    }
 }
 
 impl Debug for Download {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Download")
             .field("metadata", &self.metadata)
+            .field("range", &self.range)
             .finish()
     }
 }
@@ -398,85 +1509,256 @@ impl<const N: usize> From<[(&str, &str); N]> for StorageMetadata {
     }
 }
 
+/// What of a list response a [`Listing`] is made from. The rest of each object resource isn't
+/// sent at all.
+const LIST_FIELDS: &str = "items(name,size,updated,generation),prefixes,nextPageToken";
+/// What of a `versions=true` list response a [`VersionListing`] is made from.
+const VERSION_LIST_FIELDS: &str = "items(name,size,updated,generation,metageneration,timeCreated,\
+     timeDeleted),prefixes,nextPageToken";
+
+/// Query string for one page of `objects.list`. GCS caps `maxResults` at 1000 itself.
+fn list_query(
+    prefix: Option<&str>,
+    mode: ListingMode,
+    opts: &ListOpts,
+    versions: bool,
+    max_results: Option<u32>,
+    page_token: Option<&str>,
+) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(prefix) = prefix {
+        query.append_pair("prefix", prefix);
+    }
+    if mode == ListingMode::WithDelimiter {
+        query.append_pair("delimiter", "/");
+        if opts.include_trailing_delimiter {
+            query.append_pair("includeTrailingDelimiter", "true");
+        }
+    }
+    if versions {
+        query.append_pair("versions", "true");
+    }
+    for (param, value) in [
+        ("startOffset", &opts.start_offset),
+        ("endOffset", &opts.end_offset),
+        ("matchGlob", &opts.match_glob),
+    ] {
+        if let Some(value) = value {
+            query.append_pair(param, value);
+        }
+    }
+    if let Some(max_results) = max_results {
+        query.append_pair("maxResults", &max_results.to_string());
+    }
+    if let Some(page_token) = page_token {
+        query.append_pair("pageToken", page_token);
+    }
+    query.append_pair(
+        "fields",
+        if versions {
+            VERSION_LIST_FIELDS
+        } else {
+            LIST_FIELDS
+        },
+    );
+    query.finish()
+}
+
 #[allow(async_fn_in_trait)]
 pub trait RemoteStorage: Send + Sync + 'static {
     fn list_streaming(
         &self,
         prefix: Option<String>,
+        mode: ListingMode,
         max_keys: Option<NonZeroU32>,
+        cancel: &CancellationToken,
     ) -> impl Stream<Item = Result<Listing, DownloadError>> + Send;
+
+    /// Puts every object under `prefix` back the way it was at `timestamp`: keys created since
+    /// are deleted, and keys deleted or changed since get their generation from then back. The
+    /// bucket has to have versioning on.
+    ///
+    /// Returns the [`RecoveryPlan`] that was carried out. With `dry_run`, it's only worked out.
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<String>,
+        timestamp: SystemTime,
+        dry_run: bool,
+        cancel: &CancellationToken,
+    ) -> Result<RecoveryPlan, TimeTravelError>;
 }
 
 impl RemoteStorage for GCSBucket {
     fn list_streaming(
         &self,
         remote_prefix: Option<String>,
+        mode: ListingMode,
         max_keys: Option<NonZeroU32>,
+        cancel: &CancellationToken,
     ) -> impl Stream<Item = Result<types::Listing, types::DownloadError>> {
-        let mut max_keys = max_keys.map(|mk| mk.get() as i32);
-        let mut gcs_uri = self.bucket_name.clone() + "/o?prefix=" + &remote_prefix.unwrap();
-
-        async_stream::stream! {
-            let mut continuation_token = None;
-
-            'outer: loop {
-
-                let mut result = types::Listing::default();
-                let resp = self.list_objects(gcs_uri.clone()).await?;
-                for res in resp.contents() {
-
-                   let last_modified: SystemTime = res.updated.clone()
-                       .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
-                       .map(|s| s.into())
-                       .unwrap_or(SystemTime::now());
-
-                   let size = res.size.clone().unwrap_or("0".to_string()).parse::<u64>().unwrap();
-                   let key = res.name.clone();
-                   result.keys.push(
-                        types::ListingObject{
-                            key,
-                            last_modified,
-                            size,
-                        }
-                   );
-                   // TODO: when fork, write unit test to expose this bug.
-                   // this never gets hit, because `max_keys`  is always `None`
-                   // we take min of:
-                   // https://github.com/neondatabase/neon/blob/main/libs/remote_storage/src/lib.rs#L71
-                   // or
-                   // https://github.com/search?q=repo%3Aneondatabase%2Fneon%20%22.list_streaming%22&type=code
-                   // point being: every call to `.list_streaming()` sets `max_keys` to `None`.
-                   if let Some(mut mk) = max_keys {
-                       assert!(mk > 0);
-                       mk -= 1;
-                       if mk == 0 {
-                          println!("limit reached set by max_keys");
-                          yield Ok(result);
-                          break 'outer;
-                       }
-                       max_keys = Some(mk);
-                   };
-                }
+        self.list_streaming_with(remote_prefix, mode, max_keys, &ListOpts::default(), cancel)
+    }
 
-                yield Ok(result);
+    async fn time_travel_recover(
+        &self,
+        prefix: Option<String>,
+        timestamp: SystemTime,
+        dry_run: bool,
+        cancel: &CancellationToken,
+    ) -> Result<RecoveryPlan, TimeTravelError> {
+        let plan = self.time_travel_plan(prefix, timestamp, cancel).await?;
+        if !dry_run {
+            self.apply_recovery(&plan, cancel).await?;
+        }
+        Ok(plan)
+    }
+}
 
-                continuation_token = match resp.next_page_token {
-                    Some(token) => {
-                        gcs_uri = gcs_uri + "?pageToken=" + &token;
-                        Some(token)
-                    },
-                    None => break
+/// Pages through `objects.list`, `fetch` sending the query string of each page.
+///
+/// Whatever is left of `max_keys` goes out as `maxResults`, capped by `page_size`, so GCS never
+/// sends more than is wanted. Prefixes and objects both count against it, and the listing ends
+/// as soon as it's used up.
+fn list_pages<F, Fut>(
+    prefix: Option<String>,
+    mode: ListingMode,
+    opts: ListOpts,
+    versions: bool,
+    max_keys: Option<NonZeroU32>,
+    page_size: Option<NonZeroU32>,
+    mut fetch: F,
+) -> impl Stream<Item = Result<types::GCSListResponse, DownloadError>>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = Result<types::GCSListResponse>>,
+{
+    async_stream::stream! {
+        let mut remaining = max_keys.map(|mk| mk.get() as usize);
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let max_results = remaining
+                .map(|r| r as u32)
+                .into_iter()
+                .chain(page_size.map(|ps| ps.get()))
+                .min();
+            let query = list_query(
+                prefix.as_deref(),
+                mode,
+                &opts,
+                versions,
+                max_results,
+                continuation_token.as_deref(),
+            );
+            let mut resp = fetch(query).await?;
+            let next_page_token = resp.next_page_token.take();
+
+            if let Some(left) = &mut remaining {
+                // GCS shouldn't go over maxResults, but the cap is exact either way.
+                let prefixes = resp.prefixes.get_or_insert_with(Vec::new);
+                prefixes.truncate(*left);
+                *left -= prefixes.len();
+                let items = resp.items.get_or_insert_with(Vec::new);
+                items.truncate(*left);
+                *left -= items.len();
+                if *left == 0 {
+                    yield Ok(resp);
+                    break;
                 }
             }
+
+            yield Ok(resp);
+
+            continuation_token = match next_page_token {
+                Some(token) => Some(token),
+                None => break,
+            }
         }
     }
 }
 
+fn parse_time(rfc3339: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc3339(rfc3339).ok().map(|t| t.into())
+}
+
+fn object_size(res: &types::GCSObject) -> u64 {
+    res.size
+        .as_deref()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+fn listing(page: &types::GCSListResponse) -> Listing {
+    Listing {
+        prefixes: page.common_prefixes().to_vec(),
+        keys: page
+            .contents()
+            .iter()
+            .map(|res| ListingObject {
+                key: res.name.clone(),
+                last_modified: res
+                    .updated
+                    .as_deref()
+                    .and_then(parse_time)
+                    .unwrap_or(SystemTime::now()),
+                size: object_size(res),
+            })
+            .collect(),
+    }
+}
+
+fn version_listing(page: &types::GCSListResponse) -> Result<VersionListing, DownloadError> {
+    let generation = |res: &types::GCSObject, value: &Option<String>| {
+        value
+            .as_deref()
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| {
+                DownloadError::Other(anyhow::anyhow!(
+                    "bad generation {:?} listed for {}",
+                    value,
+                    res.name
+                ))
+            })
+    };
+    let versions = page
+        .contents()
+        .iter()
+        .map(|res| {
+            Ok(ListingVersion {
+                key: res.name.clone(),
+                generation: generation(res, &res.generation)?,
+                metageneration: generation(res, &res.metageneration)?,
+                time_created: res
+                    .time_created
+                    .as_deref()
+                    .and_then(parse_time)
+                    .ok_or_else(|| {
+                        DownloadError::Other(anyhow::anyhow!(
+                            "bad timeCreated {:?} listed for {}",
+                            res.time_created,
+                            res.name
+                        ))
+                    })?,
+                last_modified: res
+                    .updated
+                    .as_deref()
+                    .and_then(parse_time)
+                    .unwrap_or(SystemTime::now()),
+                time_deleted: res.time_deleted.as_deref().and_then(parse_time),
+                size: object_size(res),
+            })
+        })
+        .collect::<Result<_, DownloadError>>()?;
+    Ok(VersionListing {
+        prefixes: page.common_prefixes().to_vec(),
+        versions,
+    })
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use gcp_auth;
     use std::num::NonZero;
     use std::pin::pin;
     use std::sync::Arc;
@@ -495,13 +1777,26 @@ mod tests {
             token_provider: Arc::clone(&provider),
             bucket_name: BUCKET.to_string(),
             prefix_in_bucket: None,
+            session_store: None,
+            upload_md5: false,
+            timeout: DEFAULT_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            download_timeout: None,
+            upload_timeout: None,
+            delete_concurrency: DEFAULT_DELETE_CONCURRENCY,
+            max_keys_per_list_response: None,
         };
 
         // --- List: ---
         let cancel = CancellationToken::new();
         let remote_prefix = "box/tiff/2023/TN".to_string();
         let max_keys: u32 = 100;
-        let mut stream = pin!(gcs.list_streaming(Some(remote_prefix), NonZero::new(max_keys)));
+        let mut stream = pin!(gcs.list_streaming(
+            Some(remote_prefix),
+            ListingMode::NoDelimiter,
+            NonZero::new(max_keys),
+            &cancel
+        ));
         // Return some iterator
         let mut combined = stream
             .next()
@@ -521,4 +1816,724 @@ mod tests {
 
         assert_ne!(0, combined.keys.len());
     }
+
+    #[tokio::test]
+    async fn sized_stream_enforces_exact_length() {
+        async fn drain(
+            chunks: &[&'static str],
+            expected: u64,
+        ) -> (std::io::Result<usize>, Option<u64>) {
+            let mismatch = Arc::new(Mutex::new(None));
+            let source = futures::stream::iter(
+                chunks
+                    .iter()
+                    .map(|c| Ok(Bytes::from_static(c.as_bytes())))
+                    .collect::<Vec<_>>(),
+            );
+            let mut stream = pin!(sized_stream(source, expected, Arc::clone(&mismatch)));
+            let mut total = 0;
+            while let Some(item) = stream.next().await {
+                match item {
+                    Ok(bytes) => total += bytes.len(),
+                    Err(e) => return (Err(e), *mismatch.lock().unwrap()),
+                }
+            }
+            let mismatch = *mismatch.lock().unwrap();
+            (Ok(total), mismatch)
+        }
+
+        let (exact, mismatch) = drain(&["abc", "def"], 6).await;
+        assert_eq!(6, exact.unwrap());
+        assert_eq!(None, mismatch);
+
+        let (short, mismatch) = drain(&["abc"], 6).await;
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, short.unwrap_err().kind());
+        assert_eq!(Some(3), mismatch);
+
+        let (long, mismatch) = drain(&["abc", "defg"], 6).await;
+        assert_eq!(std::io::ErrorKind::InvalidData, long.unwrap_err().kind());
+        assert_eq!(Some(7), mismatch);
+    }
+
+    #[test]
+    fn download_opts_become_query_preconditions() {
+        assert_eq!("alt=json", DownloadOpts::default().query("alt=json"));
+
+        let opts = DownloadOpts {
+            if_generation_match: Some(1700000000000001),
+            if_metageneration_match: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            "alt=json&ifGenerationMatch=1700000000000001&ifMetagenerationMatch=2",
+            opts.query("alt=json")
+        );
+    }
+
+    #[test]
+    fn list_query_encodes_each_parameter_once() {
+        let none = ListOpts::default();
+        assert_eq!(
+            "",
+            without_fields(list_query(
+                None,
+                ListingMode::NoDelimiter,
+                &none,
+                false,
+                None,
+                None
+            ))
+        );
+        assert_eq!(
+            "prefix=box%2Ftiff%2F&delimiter=%2F&maxResults=10&pageToken=Cg5ib3gv%2BdGlm%3D",
+            without_fields(list_query(
+                Some("box/tiff/"),
+                ListingMode::WithDelimiter,
+                &none,
+                false,
+                Some(10),
+                Some("Cg5ib3gv+dGlm=")
+            ))
+        );
+
+        let opts = ListOpts {
+            start_offset: Some("2023/TN/a".to_string()),
+            end_offset: Some("2023/TN/m".to_string()),
+            match_glob: Some("**/*.tif".to_string()),
+            include_trailing_delimiter: true,
+        };
+        assert_eq!(
+            "delimiter=%2F&includeTrailingDelimiter=true&startOffset=2023%2FTN%2Fa\
+             &endOffset=2023%2FTN%2Fm&matchGlob=**%2F*.tif",
+            without_fields(list_query(
+                None,
+                ListingMode::WithDelimiter,
+                &opts,
+                false,
+                None,
+                None
+            ))
+        );
+        // Without a delimiter there's nothing for a trailing one to do.
+        assert_eq!(
+            "versions=true&matchGlob=**%2F*.tif",
+            without_fields(list_query(
+                None,
+                ListingMode::NoDelimiter,
+                &ListOpts {
+                    match_glob: opts.match_glob.clone(),
+                    include_trailing_delimiter: true,
+                    ..Default::default()
+                },
+                true,
+                None,
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn list_query_asks_only_for_listed_fields() {
+        let none = ListOpts::default();
+        assert_eq!(
+            "fields=items%28name%2Csize%2Cupdated%2Cgeneration%29%2Cprefixes%2CnextPageToken",
+            list_query(None, ListingMode::NoDelimiter, &none, false, None, None)
+        );
+        assert_eq!(
+            "versions=true&fields=items%28name%2Csize%2Cupdated%2Cgeneration%2Cmetageneration\
+             %2CtimeCreated%2CtimeDeleted%29%2Cprefixes%2CnextPageToken",
+            list_query(None, ListingMode::NoDelimiter, &none, true, None, None)
+        );
+
+        // What comes back for those fields is enough for a listing.
+        let page: types::GCSListResponse = serde_json::from_reader(
+            &br#"{"items":[{"name":"box/1","size":"7","generation":"1700000000000001",
+                "updated":"2024-01-01T00:00:00Z"}],"nextPageToken":"p2"}"#[..],
+        )
+        .unwrap();
+        let listing = listing(&page);
+        assert_eq!("box/1", listing.keys[0].key);
+        assert_eq!(7, listing.keys[0].size);
+        assert_eq!(
+            Some("1700000000000001"),
+            page.contents()[0].generation.as_deref()
+        );
+    }
+
+    /// `query` less the `fields` every list query ends with.
+    fn without_fields(query: String) -> String {
+        let (rest, fields) = query
+            .rsplit_once("fields=")
+            .expect("list query without fields");
+        assert!(fields.starts_with("items%28name%2Csize%2Cupdated%2Cgeneration"));
+        rest.trim_end_matches('&').to_string()
+    }
+
+    #[test]
+    fn list_response_carries_prefixes() {
+        let resp: types::GCSListResponse = serde_json::from_str(
+            r#"{"kind":"storage#objects","nextPageToken":"abc",
+                "prefixes":["box/tiff/2023/","box/tiff/2024/"]}"#,
+        )
+        .unwrap();
+        assert!(resp.contents().is_empty());
+        assert_eq!(["box/tiff/2023/", "box/tiff/2024/"], resp.common_prefixes());
+        assert_eq!(Some("abc".to_string()), resp.next_page_token);
+    }
+
+    /// Serves `pages` in order, recording the query each was asked for with.
+    async fn list_fake(
+        pages: &[String],
+        max_keys: u32,
+        page_size: Option<u32>,
+    ) -> (Vec<Listing>, Vec<String>) {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let mut pages = pages.iter();
+        let stream = list_pages(
+            Some("box/".to_string()),
+            ListingMode::WithDelimiter,
+            ListOpts::default(),
+            false,
+            NonZeroU32::new(max_keys),
+            page_size.and_then(NonZeroU32::new),
+            |query| {
+                queries.lock().unwrap().push(without_fields(query));
+                let page = pages.next().expect("asked for a page past the last");
+                std::future::ready(serde_json::from_str(page).map_err(Error::from))
+            },
+        );
+        let listings = stream.map(|page| listing(&page.unwrap())).collect().await;
+        let queries = queries.lock().unwrap().clone();
+        (listings, queries)
+    }
+
+    fn list_item(name: &str, size: u64) -> String {
+        format!(
+            r#"{{"name":"{}","bucket":"b","generation":"1","metageneration":"1",
+                "contentType":"text/plain","storageClass":"STANDARD","size":"{}",
+                "crc32c":"AAAAAA==","etag":"CAE=","timeCreated":"2024-01-01T00:00:00Z",
+                "timeStorageClassUpdated":"2024-01-01T00:00:00Z",
+                "timeFinalized":"2024-01-01T00:00:00Z"}}"#,
+            name, size
+        )
+    }
+
+    fn list_fake_pages() -> [String; 3] {
+        [
+            format!(
+                r#"{{"nextPageToken":"p2","prefixes":["box/a/"],"items":[{},{}]}}"#,
+                list_item("box/1", 1),
+                list_item("box/2", 2)
+            ),
+            format!(
+                r#"{{"nextPageToken":"p3","items":[{},{}]}}"#,
+                list_item("box/3", 3),
+                list_item("box/4", 4)
+            ),
+            format!(r#"{{"items":[{}]}}"#, list_item("box/5", 5)),
+        ]
+    }
+
+    #[tokio::test]
+    async fn max_keys_stops_listing_exactly() {
+        // Used to go on to the next page: max_keys was only counted down per key, from `None`.
+        let (listings, queries) = list_fake(&list_fake_pages(), 3, None).await;
+        assert_eq!(vec!["prefix=box%2F&delimiter=%2F&maxResults=3"], queries);
+        assert_eq!(1, listings.len());
+        assert_eq!(vec!["box/a/"], listings[0].prefixes);
+        assert_eq!(2, listings[0].keys.len());
+
+        // A page bigger than asked for is cut, prefixes counting like keys.
+        let (listings, _) = list_fake(&list_fake_pages()[..1], 2, None).await;
+        assert_eq!(1, listings[0].prefixes.len());
+        assert_eq!(1, listings[0].keys.len());
+        assert_eq!("box/1", listings[0].keys[0].key);
+    }
+
+    #[tokio::test]
+    async fn max_results_is_what_is_left_up_to_page_size() {
+        let (listings, queries) = list_fake(&list_fake_pages(), 4, Some(3)).await;
+        assert_eq!(
+            vec![
+                "prefix=box%2F&delimiter=%2F&maxResults=3",
+                "prefix=box%2F&delimiter=%2F&maxResults=1&pageToken=p2",
+            ],
+            queries
+        );
+        let keys: Vec<_> = listings
+            .iter()
+            .flat_map(|l| &l.keys)
+            .map(|k| &k.key)
+            .collect();
+        assert_eq!(vec!["box/1", "box/2", "box/3"], keys);
+
+        let (listings, queries) = list_fake(&list_fake_pages(), 0, Some(2)).await;
+        assert_eq!(3, queries.len());
+        assert!(queries.iter().all(|q| q.contains("maxResults=2")));
+        assert_eq!(5, listings.iter().map(|l| l.keys.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn version_listing_tells_live_from_noncurrent() {
+        let noncurrent = list_item("box/1", 1).replacen(
+            r#""generation":"1""#,
+            r#""generation":"1700000000000001","timeDeleted":"2024-02-01T00:00:00Z""#,
+            1,
+        );
+        let live = list_item("box/1", 2).replacen(
+            r#""generation":"1""#,
+            r#""generation":"1700000000000002""#,
+            1,
+        );
+        let page: types::GCSListResponse =
+            serde_json::from_str(&format!(r#"{{"items":[{},{}]}}"#, noncurrent, live)).unwrap();
+
+        let listing = version_listing(&page).unwrap();
+        let [old, new] = &listing.versions[..] else {
+            panic!("expected two versions, got {:?}", listing.versions);
+        };
+        assert_eq!((1700000000000001, 1), (old.generation, old.metageneration));
+        assert!(!old.is_live());
+        assert_eq!(parse_time("2024-02-01T00:00:00Z"), old.time_deleted);
+        assert_eq!(1700000000000002, new.generation);
+        assert!(new.is_live());
+        assert_eq!(2, new.size);
+
+        let bad: types::GCSListResponse = serde_json::from_str(&format!(
+            r#"{{"items":[{}]}}"#,
+            list_item("box/1", 1).replacen(r#""generation":"1""#, r#""generation":"x""#, 1)
+        ))
+        .unwrap();
+        assert!(version_listing(&bad).is_err());
+    }
+
+    #[test]
+    fn encode_key_makes_one_path_segment() {
+        assert_eq!(
+            "box%2Ftiff%2Fa%20b%2Bc.tif",
+            encode_key("box/tiff/a b+c.tif")
+        );
+        assert_eq!("caf%C3%A9~_-.txt", encode_key("café~_-.txt"));
+    }
+
+    #[test]
+    fn object_uri_keeps_nested_key_in_one_segment() {
+        assert_eq!(
+            format!(
+                "{}/o/box%2Ftiff%2Fa%20b%3Fc%25d.tif?alt=media&generation=7",
+                BUCKET
+            ),
+            object_uri(BUCKET, "box/tiff/a b?c%d.tif", "alt=media&generation=7")
+        );
+    }
+
+    #[test]
+    fn copy_opts_become_query_and_resource() {
+        assert_eq!("", CopyOpts::default().query());
+        assert!(!CopyOpts::default().rewrites_resource());
+
+        let opts = CopyOpts {
+            storage_class: Some("NEARLINE".to_string()),
+            if_source_generation_match: Some(1700000000000001),
+            if_generation_match: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            "ifSourceGenerationMatch=1700000000000001&ifGenerationMatch=0",
+            opts.query()
+        );
+        assert!(opts.rewrites_resource());
+
+        // Changing the storage class keeps the rest of the source's resource.
+        let source: types::GCSObject = serde_json::from_str(
+            r#"{
+                "name": "a.tif",
+                "generation": "1700000000000001",
+                "contentType": "image/tiff",
+                "cacheControl": "no-cache",
+                "storageClass": "STANDARD",
+                "crc32c": "yZRlqg==",
+                "metadata": {"tile": "TN"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "name": "b.tif",
+                "contentType": "image/tiff",
+                "cacheControl": "no-cache",
+                "metadata": {"tile": "TN"},
+                "storageClass": "NEARLINE",
+            }),
+            serde_json::to_value(opts.destination_resource("b.tif", &source)).unwrap()
+        );
+
+        let mut metadata = HashMap::new();
+        metadata.insert("tile".to_string(), "KY".to_string());
+        let opts = CopyOpts {
+            metadata: Some(StorageMetadata(metadata)),
+            ..Default::default()
+        };
+        let resource = opts.destination_resource("b.tif", &source);
+        assert_eq!("KY", resource.metadata.unwrap()["tile"]);
+        assert_eq!(Some("STANDARD"), resource.storage_class.as_deref());
+        assert_eq!(Some("image/tiff"), resource.content_type.as_deref());
+    }
+
+    fn rewrite_response(rewritten: u64, token: Option<&str>, resource: bool) -> String {
+        serde_json::json!({
+            "totalBytesRewritten": rewritten.to_string(),
+            "objectSize": "30",
+            "done": rewritten == 30,
+            "rewriteToken": token,
+            "resource": resource.then(|| serde_json::json!({"name": "b.tif"})),
+        })
+        .to_string()
+    }
+
+    /// Runs the `rewriteToken` loop over `responses`, returning its result, the token each
+    /// call got and the progress reported.
+    async fn fake_rewrite(
+        responses: Vec<String>,
+    ) -> (Result<types::GCSObject>, Vec<Option<String>>, Vec<u64>) {
+        let mut responses = responses.into_iter();
+        let mut tokens = Vec::new();
+        let mut progress = Vec::new();
+        let result = rewrite_calls(
+            |token| {
+                tokens.push(token);
+                let response = responses.next().unwrap();
+                Box::pin(async move { Ok(serde_json::from_str(&response)?) })
+            },
+            |p| {
+                assert_eq!(30, p.object_size);
+                progress.push(p.bytes_rewritten)
+            },
+        )
+        .await;
+        (result, tokens, progress)
+    }
+
+    #[tokio::test]
+    async fn rewrite_carries_the_token_to_the_next_call() {
+        let (object, tokens, progress) = fake_rewrite(vec![
+            rewrite_response(10, Some("t1"), false),
+            rewrite_response(20, Some("t2"), false),
+            rewrite_response(30, None, true),
+        ])
+        .await;
+        assert_eq!("b.tif", object.unwrap().name);
+        assert_eq!(
+            vec![None, Some("t1".to_string()), Some("t2".to_string())],
+            tokens
+        );
+        assert_eq!(vec![10, 20, 30], progress);
+
+        let (object, tokens, _) = fake_rewrite(vec![rewrite_response(10, None, false)]).await;
+        let err = object.unwrap_err().to_string();
+        assert!(err.contains("no rewriteToken"), "{}", err);
+        assert_eq!(1, tokens.len());
+
+        let (object, _, _) = fake_rewrite(vec![rewrite_response(30, None, false)]).await;
+        let err = object.unwrap_err().to_string();
+        assert!(err.contains("no resource"), "{}", err);
+    }
+
+    #[test]
+    fn content_range_is_end_inclusive() {
+        assert_eq!(
+            Some(ByteRange {
+                start: 0,
+                end: 100,
+                total: Some(1234)
+            }),
+            ByteRange::from_content_range("bytes 0-99/1234")
+        );
+        assert_eq!(
+            Some(ByteRange {
+                start: 1134,
+                end: 1234,
+                total: None
+            }),
+            ByteRange::from_content_range("bytes 1134-1233/*")
+        );
+        assert!(ByteRange::from_content_range("bytes 0-1233/1234")
+            .unwrap()
+            .is_whole_object());
+        assert_eq!(None, ByteRange::from_content_range("bytes */1234"));
+    }
+
+    #[tokio::test]
+    async fn slice_stream_cuts_across_chunks() {
+        let source = futures::stream::iter(
+            ["abc", "defg", "hij"]
+                .iter()
+                .map(|c| Ok(Bytes::from_static(c.as_bytes())))
+                .collect::<Vec<_>>(),
+        );
+        let sliced: Vec<Bytes> = slice_stream(source, 2, 6)
+            .map(|item| item.unwrap())
+            .collect()
+            .await;
+        assert_eq!(b"cdefgh".to_vec(), sliced.concat());
+    }
+
+    /// A stream of `chunks`, then `error` if there is one.
+    fn chunk_stream(chunks: &[&'static str], error: Option<std::io::ErrorKind>) -> DownloadStream {
+        let items = chunks
+            .iter()
+            .map(|c| Ok(Bytes::from_static(c.as_bytes())))
+            .chain(error.map(|kind| Err(std::io::Error::from(kind))))
+            .collect::<Vec<_>>();
+        Box::pin(futures::stream::iter(items))
+    }
+
+    type Calls = Arc<std::sync::Mutex<Vec<(u64, u64)>>>;
+
+    /// A resume step that hands out `streams` in turn, noting the range asked for each time.
+    fn fake_resumer(
+        streams: Vec<std::io::Result<DownloadStream>>,
+    ) -> (
+        impl Fn(u64, u64) -> ResumeFuture + Send + Sync + 'static,
+        Calls,
+    ) {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let streams = std::sync::Mutex::new(streams.into_iter());
+        let seen = Arc::clone(&calls);
+        let resume = move |offset, end| -> ResumeFuture {
+            seen.lock().unwrap().push((offset, end));
+            let next = streams.lock().unwrap().next();
+            Box::pin(async move {
+                next.unwrap_or_else(|| Err(std::io::Error::other("resumed too often")))
+            })
+        };
+        (resume, calls)
+    }
+
+    async fn collect_download(stream: DownloadStream) -> (Vec<u8>, Option<std::io::ErrorKind>) {
+        let mut stream = stream;
+        let mut bytes = Vec::new();
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(chunk) => bytes.extend_from_slice(&chunk),
+                Err(e) => {
+                    assert!(stream.next().await.is_none());
+                    return (bytes, Some(e.kind()));
+                }
+            }
+        }
+        (bytes, None)
+    }
+
+    #[tokio::test]
+    async fn resuming_stream_picks_up_at_the_delivered_offset() {
+        let first = chunk_stream(&["abc", "de"], Some(std::io::ErrorKind::ConnectionReset));
+        let (resume, calls) = fake_resumer(vec![Ok(chunk_stream(&["fgh", "ij"], None))]);
+
+        let (bytes, err) = collect_download(resuming_stream(first, resume, 10, 20, 3)).await;
+        assert_eq!(b"abcdefghij".to_vec(), bytes);
+        assert_eq!(None, err);
+        assert_eq!(vec![(15, 20)], *calls.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn resuming_stream_resumes_after_an_early_end() {
+        // The first body just stops short, and so does the first resumed one.
+        let first = chunk_stream(&["abc"], None);
+        let (resume, calls) = fake_resumer(vec![
+            Ok(chunk_stream(&["de"], None)),
+            Ok(chunk_stream(&["fg"], None)),
+        ]);
+
+        let (bytes, err) = collect_download(resuming_stream(first, resume, 0, 7, 1)).await;
+        assert_eq!(b"abcdefg".to_vec(), bytes);
+        assert_eq!(None, err);
+        assert_eq!(vec![(3, 7), (5, 7)], *calls.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn resuming_stream_gives_up_after_the_attempts() {
+        let first = chunk_stream(&["abc"], Some(std::io::ErrorKind::ConnectionReset));
+        let (resume, calls) = fake_resumer(vec![
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)),
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+        ]);
+
+        let (bytes, err) = collect_download(resuming_stream(first, resume, 0, 7, 2)).await;
+        assert_eq!(b"abc".to_vec(), bytes);
+        assert_eq!(Some(std::io::ErrorKind::TimedOut), err);
+        assert_eq!(vec![(3, 7), (3, 7)], *calls.lock().unwrap());
+
+        // Without attempts, the first error is the end of it.
+        let first = chunk_stream(&["abc"], Some(std::io::ErrorKind::ConnectionReset));
+        let (resume, calls) = fake_resumer(Vec::new());
+        let (bytes, err) = collect_download(resuming_stream(first, resume, 0, 7, 0)).await;
+        assert_eq!(b"abc".to_vec(), bytes);
+        assert_eq!(Some(std::io::ErrorKind::ConnectionReset), err);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumed_body_checks_where_the_response_starts() {
+        // A server ignoring the Range header sends the whole object, which gets cut down.
+        let whole = chunk_stream(&["abcd", "efgh", "ij"], None);
+        let body = resumed_body(StatusCode::OK, None, whole, 3, 8).unwrap();
+        assert_eq!((b"defgh".to_vec(), None), collect_download(body).await);
+
+        let partial = chunk_stream(&["defgh"], None);
+        let range = ByteRange::from_content_range("bytes 3-7/10");
+        let body = resumed_body(StatusCode::PARTIAL_CONTENT, range, partial, 3, 8).unwrap();
+        assert_eq!((b"defgh".to_vec(), None), collect_download(body).await);
+
+        let elsewhere = ByteRange::from_content_range("bytes 0-7/10");
+        let body = chunk_stream(&["abcdefgh"], None);
+        assert!(resumed_body(StatusCode::PARTIAL_CONTENT, elsewhere, body, 3, 8).is_err());
+        let body = chunk_stream(&[], None);
+        assert!(resumed_body(StatusCode::PARTIAL_CONTENT, None, body, 3, 8).is_err());
+        let body = chunk_stream(&[], None);
+        assert!(resumed_body(StatusCode::SERVICE_UNAVAILABLE, None, body, 3, 8).is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_objects_batches_in_order_within_the_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+        let names: Vec<String> = (0..450).map(|i| format!("k/{:03}", i)).collect();
+        let paths: Vec<&str> = names.iter().map(String::as_str).collect();
+        let in_flight = &AtomicUsize::new(0);
+        let most_in_flight = &AtomicUsize::new(0);
+        let batches = &Mutex::new(Vec::new());
+        let send = move |indices: Vec<usize>| -> DeleteBatch<'_> {
+            Box::pin(async move {
+                let now = in_flight.fetch_add(1, SeqCst) + 1;
+                most_in_flight.fetch_max(now, SeqCst);
+                batches.lock().unwrap().push((indices[0], indices.len()));
+                // Later batches answer sooner, so they finish out of order.
+                tokio::time::sleep(Duration::from_millis(50 - indices[0] as u64 / 10)).await;
+                in_flight.fetch_sub(1, SeqCst);
+                Ok(indices
+                    .iter()
+                    .map(|i| match i % 7 {
+                        0 => Ok(StatusCode::NOT_FOUND),
+                        _ => Ok(StatusCode::NO_CONTENT),
+                    })
+                    .collect())
+            })
+        };
+
+        let concurrency = NonZeroUsize::new(2).unwrap();
+        let outcomes = delete_in_batches(&paths, concurrency, &CancellationToken::new(), send)
+            .await
+            .unwrap();
+
+        let expected: Vec<_> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| match i % 7 {
+                0 => (name.clone(), DeleteOutcome::NotFound),
+                _ => (name.clone(), DeleteOutcome::Deleted),
+            })
+            .collect();
+        assert_eq!(expected, outcomes);
+        let mut batches = batches.lock().unwrap().clone();
+        batches.sort();
+        assert_eq!(
+            vec![(0, 100), (100, 100), (200, 100), (300, 100), (400, 50)],
+            batches
+        );
+        assert_eq!(2, most_in_flight.load(SeqCst));
+    }
+
+    #[tokio::test]
+    async fn delete_objects_retries_only_transient_batch_failures() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+        let paths = ["a", "b"];
+        let cancel = CancellationToken::new();
+        let delete = |failure: fn() -> anyhow::Error| {
+            let calls = AtomicUsize::new(0);
+            let send = move |indices: Vec<usize>| -> DeleteBatch<'static> {
+                let first = calls.fetch_add(1, SeqCst) == 0;
+                Box::pin(async move {
+                    match first {
+                        true => Err(failure()),
+                        false => Ok(vec![Ok(StatusCode::NO_CONTENT); indices.len()]),
+                    }
+                })
+            };
+            delete_in_batches(&paths, NonZeroUsize::MIN, &cancel, send)
+        };
+
+        // Unavailable for a moment, then through.
+        let outcomes = delete(|| BatchStatusError(StatusCode::SERVICE_UNAVAILABLE).into())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                ("a".to_string(), DeleteOutcome::Deleted),
+                ("b".to_string(), DeleteOutcome::Deleted)
+            ],
+            outcomes
+        );
+
+        // Rejections that sending again won't change.
+        let rejections: [fn() -> anyhow::Error; 2] = [
+            || BatchStatusError(StatusCode::FORBIDDEN).into(),
+            || anyhow::anyhow!("batch response had no boundary"),
+        ];
+        for rejection in rejections {
+            let Err(DeleteError::Failed(failed)) = delete(rejection).await else {
+                panic!("a rejected batch was retried");
+            };
+            let keys: Vec<_> = failed.iter().map(|f| f.key.as_str()).collect();
+            assert_eq!(vec!["a", "b"], keys);
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_generations_reports_changed_keys() {
+        // What a guarded delete of a key written since comes back with.
+        let send = |indices: Vec<usize>| -> DeleteBatch<'static> {
+            Box::pin(async move {
+                Ok(indices
+                    .iter()
+                    .map(|i| match i {
+                        1 => Ok(StatusCode::PRECONDITION_FAILED),
+                        _ => Ok(StatusCode::NO_CONTENT),
+                    })
+                    .collect())
+            })
+        };
+        let paths = ["a", "b", "c"];
+        let cancel = CancellationToken::new();
+        let result = delete_in_batches(&paths, NonZeroUsize::MIN, &cancel, send);
+        let Err(DeleteError::Failed(failed)) = result.await else {
+            panic!("a failed precondition counted as deleted");
+        };
+        assert_eq!(1, failed.len());
+        assert_eq!("b", failed[0].key);
+        assert_eq!(Some(StatusCode::PRECONDITION_FAILED), failed[0].status);
+
+        let TimeTravelError::DeleteFailed(failed) = DeleteError::Failed(failed).into() else {
+            panic!("delete failures didn't carry over");
+        };
+        assert_eq!("b", failed[0].key);
+    }
+
+    #[test]
+    fn multipart_related_frame_wraps_resource_and_media() {
+        let (head, tail) = multipart_related_frame("b0und", r#"{"name":"a.tif"}"#, "image/tiff");
+        let body = head + "MEDIA" + &tail;
+
+        assert_eq!(
+            "--b0und\r\n\
+            Content-Type: application/json; charset=UTF-8\r\n\r\n\
+            {\"name\":\"a.tif\"}\r\n\
+            --b0und\r\n\
+            Content-Type: image/tiff\r\n\r\n\
+            MEDIA\r\n\
+            --b0und--\r\n",
+            body
+        );
+    }
 }
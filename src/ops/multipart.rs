@@ -0,0 +1,315 @@
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use http::{HeaderMap, HeaderName, HeaderValue};
+
+// Plenty for a batch part or the response inside it; GCS sends fewer than a dozen.
+const MAX_HEADERS: usize = 64;
+
+/// The `boundary` parameter of a `multipart/*` Content-Type, unquoted if it was quoted, e.g.
+/// `multipart/mixed; boundary="===============7330845974216740156=="`.
+pub fn boundary(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim();
+        match value.strip_prefix('"') {
+            Some(quoted) => {
+                let mut unquoted = String::new();
+                let mut chars = quoted.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => return Some(unquoted),
+                        '\\' => unquoted.push(chars.next()?),
+                        c => unquoted.push(c),
+                    }
+                }
+                None
+            }
+            None => Some(value.to_string()),
+        }
+    })
+}
+
+/// One body part: its MIME headers and its content.
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Before the first delimiter.
+    Preamble,
+    /// Just after a delimiter line, in the content of a part.
+    Part,
+    /// After the close delimiter; the epilogue is ignored.
+    Done,
+}
+
+/// `multipart/mixed` parser (RFC 2046 §5.1.1) that takes the body in whatever chunks it
+/// arrives in and hands back each part once the delimiter after it has come in.
+///
+/// A delimiter only counts at the start of a line and when the boundary isn't just the start of
+/// a longer word, so a body that mentions the boundary text doesn't get cut. Bare `LF` line
+/// endings are accepted as well as `CRLF`.
+pub struct MultipartParser {
+    /// `--` and the boundary.
+    delimiter: Vec<u8>,
+    buffer: BytesMut,
+    /// Where to pick up looking for the next delimiter: everything before it has been searched.
+    scanned: usize,
+    state: State,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str) -> Self {
+        Self {
+            delimiter: format!("--{}", boundary).into_bytes(),
+            buffer: BytesMut::new(),
+            scanned: 0,
+            state: State::Preamble,
+        }
+    }
+
+    /// Adds `chunk` and returns the parts it completed.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Part>> {
+        if self.state == State::Done {
+            return Ok(Vec::new());
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        let mut parts = Vec::new();
+        while let Some(delimiter) = self.next_delimiter() {
+            let Some(line_end) = delimiter.line_end else {
+                // The rest of the delimiter line hasn't arrived yet.
+                break;
+            };
+
+            let content = self.buffer.split_to(delimiter.start).freeze();
+            if self.state == State::Part {
+                parts.push(parse_part(content)?);
+            }
+            self.buffer.advance(line_end - delimiter.start);
+            self.scanned = 0;
+            self.state = if delimiter.close {
+                State::Done
+            } else {
+                State::Part
+            };
+            if self.state == State::Done {
+                self.buffer.clear();
+                break;
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Checks the body ended with a close delimiter, i.e. no part was cut off.
+    pub fn finish(self) -> Result<()> {
+        match self.state {
+            State::Done => Ok(()),
+            State::Preamble => Err(anyhow::anyhow!("multipart body had no delimiter")),
+            State::Part => Err(anyhow::anyhow!(
+                "multipart body ended without a close delimiter"
+            )),
+        }
+    }
+
+    fn next_delimiter(&mut self) -> Option<Delimiter> {
+        let buffer = &self.buffer[..];
+        let mut from = self.scanned;
+        loop {
+            let Some(found) = find(&buffer[from..], &self.delimiter).map(|at| from + at) else {
+                // Keep enough of the tail to spot a delimiter that straddles the next chunk.
+                self.scanned = buffer.len().saturating_sub(self.delimiter.len() + 2);
+                return None;
+            };
+            from = found + 1;
+
+            // The delimiter has to start a line; the line break before it belongs to it.
+            let start = match found {
+                0 if self.state == State::Preamble => 0,
+                0 => continue,
+                at if buffer[at - 1] != b'\n' => continue,
+                at if at >= 2 && buffer[at - 2] == b'\r' => at - 2,
+                at => at - 1,
+            };
+
+            let after = &buffer[found + self.delimiter.len()..];
+            if after.len() < 2 && b"--".starts_with(after) {
+                // Not enough in yet to tell a close delimiter, or a longer word, from this.
+                self.scanned = found.saturating_sub(2);
+                return Some(Delimiter {
+                    start,
+                    line_end: None,
+                    close: false,
+                });
+            }
+            let close = after.starts_with(b"--");
+            if !close && !matches!(after[0], b' ' | b'\t' | b'\r' | b'\n') {
+                continue;
+            }
+
+            // Transport padding, then the end of the line.
+            let line_end = after
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|at| found + self.delimiter.len() + at + 1);
+            let line_end = match line_end {
+                Some(line_end) => Some(line_end),
+                // Nothing has to follow a close delimiter.
+                None if close => Some(buffer.len()),
+                None => {
+                    self.scanned = found.saturating_sub(2);
+                    None
+                }
+            };
+            return Some(Delimiter {
+                start,
+                line_end,
+                close,
+            });
+        }
+    }
+}
+
+struct Delimiter {
+    /// Where the line break in front of it starts, i.e. where the part before it ends.
+    start: usize,
+    /// Just past the end of its line, if that has arrived.
+    line_end: Option<usize>,
+    close: bool,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_part(content: Bytes) -> Result<Part> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let (len, parsed) = match httparse::parse_headers(&content, &mut headers)? {
+        httparse::Status::Complete(complete) => complete,
+        httparse::Status::Partial => {
+            return Err(anyhow::anyhow!("multipart part ended inside its headers"))
+        }
+    };
+    Ok(Part {
+        headers: header_map(parsed)?,
+        body: content.slice(len..),
+    })
+}
+
+/// An HTTP response carried as a part (`Content-Type: application/http`).
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: http::StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Parses the status line, headers and body of an `application/http` part. The body is cut to
+/// `Content-Length` when there is one.
+pub fn parse_http_response(content: &Bytes) -> Result<HttpResponse> {
+    // Some servers put an empty line before the status line.
+    let skip = content
+        .iter()
+        .position(|b| !matches!(b, b'\r' | b'\n'))
+        .unwrap_or(content.len());
+    let content = content.slice(skip..);
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let len = match response.parse(&content)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => {
+            return Err(anyhow::anyhow!("part ended inside its HTTP response head"))
+        }
+    };
+    let status = http::StatusCode::from_u16(
+        response
+            .code
+            .ok_or_else(|| anyhow::anyhow!("HTTP response had no status"))?,
+    )?;
+    let headers = header_map(response.headers)?;
+
+    let mut body = content.slice(len..);
+    if let Some(length) = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+    {
+        body.truncate(length);
+    }
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn header_map(headers: &[httparse::Header]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for header in headers {
+        map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_in_chunks(body: &[u8], boundary: &str, chunk_size: usize) -> Vec<Part> {
+        let mut parser = MultipartParser::new(boundary);
+        let mut parts = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            parts.extend(parser.feed(chunk).unwrap());
+        }
+        parser.finish().unwrap();
+        parts
+    }
+
+    #[test]
+    fn boundary_is_unquoted() {
+        assert_eq!(
+            Some("batch_pK7JBAk73-E=_AA5eFwv4m2Q=".to_string()),
+            boundary("multipart/mixed; boundary=batch_pK7JBAk73-E=_AA5eFwv4m2Q=")
+        );
+        assert_eq!(
+            Some("===============7330845974216740156==".to_string()),
+            boundary(
+                "multipart/mixed; charset=utf-8; BOUNDARY=\"===============7330845974216740156==\""
+            )
+        );
+        assert_eq!(None, boundary("multipart/mixed"));
+    }
+
+    #[test]
+    fn delimiters_only_count_at_line_start() {
+        let body = b"preamble\r\n--b\r\nContent-ID: 1\r\n\r\nmentions --b and\r\n--bb too\r\n--b \t\r\n\r\nlf only\n--b--\r\nepilogue";
+        for chunk_size in [1, 3, 7, body.len()] {
+            let parts = parse_in_chunks(body, "b", chunk_size);
+            assert_eq!(2, parts.len(), "chunks of {}", chunk_size);
+            assert_eq!("1", parts[0].headers.get("content-id").unwrap());
+            assert_eq!(&b"mentions --b and\r\n--bb too"[..], &parts[0].body[..]);
+            assert!(parts[1].headers.is_empty());
+            assert_eq!(&b"lf only"[..], &parts[1].body[..]);
+        }
+    }
+
+    #[test]
+    fn missing_close_delimiter_is_an_error() {
+        let mut parser = MultipartParser::new("b");
+        let parts = parser.feed(b"--b\r\n\r\none\r\n--b\r\n\r\ntwo").unwrap();
+        assert_eq!(1, parts.len());
+        assert!(parser.finish().is_err());
+    }
+}
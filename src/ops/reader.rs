@@ -0,0 +1,368 @@
+use crate::ops::gcs_bucket::{DownloadOpts, GCSBucket};
+use crate::ops::support::timeout_or_cancel;
+use crate::ops::types::{DownloadError, GCSObject};
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures_util::StreamExt;
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// How an [`ObjectReader`] fetches and keeps bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct ReaderOptions {
+    /// Granularity of range requests and of the cache.
+    pub block_size: usize,
+    /// Extra blocks fetched after the one a read missed on, in the same request.
+    pub read_ahead_blocks: usize,
+    /// Blocks kept around, least recently used dropped first.
+    pub cache_blocks: usize,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 1024 * 1024,
+            read_ahead_blocks: 1,
+            cache_blocks: 32,
+        }
+    }
+}
+
+/// `AsyncRead + AsyncSeek` over one generation of a GCS object, for TIFF/GeoTIFF and zip
+/// parsers that jump around a file.
+///
+/// A read that misses the cache becomes a range request for its block plus the read-ahead.
+/// Every request is pinned to the generation seen when the reader was opened: if the object is
+/// overwritten mid-read, reads fail with an error wrapping [`DownloadError::NotFound`] instead
+/// of mixing generations.
+///
+/// Each range request, its body included, gets the bucket's timeout; one that runs out fails the
+/// read with an error wrapping [`DownloadError::Timeout`]. Once `cancel` fires, reads fail with
+/// an error wrapping [`DownloadError::Cancelled`].
+pub struct ObjectReader {
+    fetch_range: FetchRange,
+    object: GCSObject,
+    size: u64,
+    options: ReaderOptions,
+    position: u64,
+    cache: BlockCache,
+    pending: Option<PendingFetch>,
+}
+
+/// Bytes `start..end` of the object, all of them.
+type FetchRange = Box<dyn Fn(u64, u64) -> BoxFuture<'static, std::io::Result<Bytes>> + Send + Sync>;
+
+/// A range request in flight: its first block, how many blocks it covers, and the blocks.
+type PendingFetch = (u64, u64, BoxFuture<'static, std::io::Result<Vec<Bytes>>>);
+
+impl ObjectReader {
+    pub async fn open(
+        bucket: Arc<GCSBucket>,
+        key: String,
+        options: ReaderOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Self, DownloadError> {
+        if options.block_size == 0 || options.cache_blocks == 0 {
+            return Err(DownloadError::BadInput(anyhow::anyhow!(
+                "block_size and cache_blocks have to be non-zero"
+            )));
+        }
+
+        let object = bucket.object_metadata(&key, cancel).await?;
+        let size = object
+            .size
+            .as_deref()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| DownloadError::Other(anyhow::anyhow!("object resource had no size")))?;
+
+        let generation = object.clone();
+        let cancel = cancel.clone();
+        let fetch_range: FetchRange = Box::new(move |start, end| {
+            let bucket = Arc::clone(&bucket);
+            let key = key.clone();
+            let object = generation.clone();
+            let cancel = cancel.clone();
+            Box::pin(async move {
+                let deadline = Instant::now() + bucket.timeout;
+                let opts = DownloadOpts {
+                    byte_start: start,
+                    byte_end: Some(end),
+                    ..Default::default()
+                };
+                let download = bucket
+                    .download_generation_range(object, &key, &opts, deadline, &cancel)
+                    .await
+                    .map_err(std::io::Error::other)?;
+
+                // The body is drained under the same deadline as the headers.
+                let drain = async {
+                    let mut body = BytesMut::with_capacity((end - start) as usize);
+                    let mut stream = download.download_stream;
+                    while let Some(bytes) = stream.next().await {
+                        body.extend_from_slice(&bytes?);
+                    }
+                    Ok(body.freeze())
+                };
+                timeout_or_cancel(drain, deadline, &cancel)
+                    .await
+                    .map_err(|e| std::io::Error::other(DownloadError::from(e)))?
+            })
+        });
+
+        Ok(Self::new(object, size, options, fetch_range))
+    }
+
+    fn new(object: GCSObject, size: u64, options: ReaderOptions, fetch_range: FetchRange) -> Self {
+        Self {
+            fetch_range,
+            object,
+            size,
+            options,
+            position: 0,
+            cache: BlockCache::new(options.cache_blocks),
+            pending: None,
+        }
+    }
+
+    /// The object resource of the generation being read.
+    pub fn object(&self) -> &GCSObject {
+        &self.object
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn fetch(&self, first: u64, count: u64) -> BoxFuture<'static, std::io::Result<Vec<Bytes>>> {
+        let block_size = self.options.block_size;
+        let start = first * block_size as u64;
+        let end = ((first + count) * block_size as u64).min(self.size);
+        let name = self.object.name.clone();
+        let range = (self.fetch_range)(start, end);
+
+        Box::pin(async move {
+            let mut body = range.await?;
+            if body.len() as u64 != end - start {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "range {}..{} of {} came back {} bytes long",
+                        start,
+                        end,
+                        name,
+                        body.len()
+                    ),
+                ));
+            }
+
+            let mut blocks = Vec::new();
+            while !body.is_empty() {
+                blocks.push(body.split_to(block_size.min(body.len())));
+            }
+            Ok(blocks)
+        })
+    }
+}
+
+impl AsyncRead for ObjectReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.position >= this.size || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let block_size = this.options.block_size as u64;
+        let block = this.position / block_size;
+        loop {
+            if let Some(bytes) = this.cache.get(block) {
+                let offset = (this.position - block * block_size) as usize;
+                let n = buf.remaining().min(bytes.len() - offset);
+                buf.put_slice(&bytes[offset..offset + n]);
+                this.position += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            match &mut this.pending {
+                Some((first, count, fut)) if (*first..*first + *count).contains(&block) => {
+                    let first = *first;
+                    let result = ready!(fut.as_mut().poll(cx));
+                    this.pending = None;
+                    // Most recently used last, so the block that was asked for goes in last.
+                    for (i, bytes) in result?.into_iter().enumerate().rev() {
+                        this.cache.insert(first + i as u64, bytes);
+                    }
+                }
+                // Nothing in flight, or a request for somewhere we seeked away from.
+                _ => {
+                    let last_block = this.size.div_ceil(block_size);
+                    let count = (1 + this.options.read_ahead_blocks as u64).min(last_block - block);
+                    this.pending = Some((block, count, this.fetch(block, count)));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for ObjectReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+        // Past the end is fine, reads there just return nothing.
+        this.position = position.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+/// Least recently used blocks, by block index. Small enough that a linear scan is fine.
+struct BlockCache {
+    capacity: usize,
+    blocks: VecDeque<(u64, Bytes)>,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn get(&mut self, index: u64) -> Option<Bytes> {
+        let at = self.blocks.iter().position(|(i, _)| *i == index)?;
+        let entry = self.blocks.remove(at)?;
+        let bytes = entry.1.clone();
+        self.blocks.push_back(entry);
+        Some(bytes)
+    }
+
+    fn insert(&mut self, index: u64, bytes: Bytes) {
+        if let Some(at) = self.blocks.iter().position(|(i, _)| *i == index) {
+            self.blocks.remove(at);
+        }
+        if self.blocks.len() == self.capacity {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((index, bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    const DATA: &[u8] = b"0123456789";
+
+    type Calls = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    /// A reader over [`DATA`] in blocks of 4, fetching from memory and noting each range asked
+    /// for. `short` cuts every response one byte short.
+    fn fake_reader(short: bool) -> (ObjectReader, Calls) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&calls);
+        let fetch_range: FetchRange = Box::new(move |start, end| {
+            seen.lock().unwrap().push((start, end));
+            let end = if short { end - 1 } else { end };
+            let bytes = Bytes::from_static(&DATA[start as usize..end as usize]);
+            Box::pin(async move { Ok(bytes) })
+        });
+        let object = serde_json::from_str(r#"{"name":"a.tif"}"#).unwrap();
+        let options = ReaderOptions {
+            block_size: 4,
+            read_ahead_blocks: 1,
+            cache_blocks: 2,
+        };
+        let reader = ObjectReader::new(object, DATA.len() as u64, options, fetch_range);
+        (reader, calls)
+    }
+
+    #[tokio::test]
+    async fn reads_across_blocks_with_read_ahead() {
+        let (mut reader, calls) = fake_reader(false);
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).await.unwrap();
+        assert_eq!(DATA, &all[..]);
+        // The block missed on plus one of read-ahead, and the last one cut at the end.
+        assert_eq!(vec![(0, 8), (8, 10)], *calls.lock().unwrap());
+
+        // Straddling blocks 1 and 2, both still cached.
+        reader.seek(SeekFrom::Start(6)).await.unwrap();
+        let mut straddling = [0; 3];
+        reader.read_exact(&mut straddling).await.unwrap();
+        assert_eq!(b"678", &straddling);
+        assert_eq!(2, calls.lock().unwrap().len());
+
+        // Block 0 was dropped from the cache.
+        reader.seek(SeekFrom::Current(-8)).await.unwrap();
+        let mut first = [0; 2];
+        reader.read_exact(&mut first).await.unwrap();
+        assert_eq!(b"12", &first);
+        assert_eq!(vec![(0, 8), (8, 10), (0, 8)], *calls.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn seeks_to_and_past_the_end() {
+        let (mut reader, calls) = fake_reader(false);
+        let mut rest = Vec::new();
+
+        assert_eq!(10, reader.seek(SeekFrom::End(0)).await.unwrap());
+        assert_eq!(0, reader.read_to_end(&mut rest).await.unwrap());
+        assert_eq!(25, reader.seek(SeekFrom::Start(25)).await.unwrap());
+        assert_eq!(0, reader.read_to_end(&mut rest).await.unwrap());
+        assert!(calls.lock().unwrap().is_empty());
+
+        assert_eq!(7, reader.seek(SeekFrom::End(-3)).await.unwrap());
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(b"789", &rest[..]);
+        assert_eq!(vec![(4, 10)], *calls.lock().unwrap());
+
+        let err = reader.seek(SeekFrom::Current(-11)).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(10, reader.stream_position().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn short_range_is_an_error() {
+        let (mut reader, _) = fake_reader(true);
+        let mut all = Vec::new();
+        let err = reader.read_to_end(&mut all).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn block_cache_drops_least_recently_used() {
+        let mut cache = BlockCache::new(2);
+        cache.insert(0, Bytes::from_static(b"zero"));
+        cache.insert(1, Bytes::from_static(b"one"));
+        assert!(cache.get(0).is_some());
+
+        cache.insert(2, Bytes::from_static(b"two"));
+        assert_eq!(None, cache.get(1));
+        assert_eq!(Some(Bytes::from_static(b"zero")), cache.get(0));
+        assert_eq!(Some(Bytes::from_static(b"two")), cache.get(2));
+    }
+}
@@ -0,0 +1,1071 @@
+use crate::ops::checksum::Hasher;
+use crate::ops::gcs_bucket::{GCSBucket, SCOPES};
+use crate::ops::session_store::{SessionStore, StoredSession};
+use crate::ops::support::timeout_or_cancel;
+use crate::ops::types::{GCSObject, TimeoutOrCancel, UploadError};
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::stream::Stream;
+use futures_util::StreamExt;
+use http::StatusCode;
+use reqwest::{header, Client};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::time::Instant;
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+
+// https://cloud.google.com/storage/docs/performing-resumable-uploads#chunked-upload
+// Every chunk but the last has to be a multiple of 256 KiB.
+const CHUNK_ALIGNMENT: usize = 256 * 1024;
+pub const RESUMABLE_CHUNK_SIZE: usize = 32 * CHUNK_ALIGNMENT;
+const MAX_RETRIES: u32 = 5;
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// An open resumable upload session. The session URI is good for a week.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResumableUpload {
+    /// The `Location` header returned when the session was started.
+    pub session_uri: String,
+    pub name: String,
+    pub total_size: Option<u64>,
+    /// Bytes GCS has acknowledged so far.
+    pub committed: u64,
+}
+
+/// What the session URI says about the upload.
+#[derive(Debug)]
+pub enum UploadStatus {
+    /// `200 OK` / `201 Created`: the object exists.
+    Complete(Box<GCSObject>),
+    /// `308 Resume Incomplete`: pick up from `committed`.
+    Incomplete { committed: u64 },
+    /// `404 Not Found` / `410 Gone`: the session is gone, start a new one.
+    Expired,
+}
+
+enum ChunkError {
+    /// `5xx`, `429` or a dropped connection: ask for the status and go again.
+    Retryable(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl ChunkError {
+    fn into_inner(self) -> anyhow::Error {
+        match self {
+            ChunkError::Retryable(e) | ChunkError::Fatal(e) => e,
+        }
+    }
+}
+
+impl GCSBucket {
+    /// Starts a resumable upload session for `name`. Nothing is uploaded yet.
+    pub async fn start_resumable_upload(
+        &self,
+        name: &str,
+        total_size: Option<u64>,
+        cancel: &CancellationToken,
+    ) -> Result<ResumableUpload> {
+        let deadline = Instant::now() + self.timeout;
+        let encoded_name: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        let uri = format!(
+            "{}/o?uploadType=resumable&name={}",
+            self.upload_uri(),
+            encoded_name
+        );
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, header::HeaderValue::from(0));
+        if let Some(total_size) = total_size {
+            headers.insert(
+                header::HeaderName::from_static("x-upload-content-length"),
+                header::HeaderValue::from(total_size),
+            );
+        }
+
+        let req = resumable_client()?
+            .post(uri)
+            .headers(headers)
+            .bearer_auth(self.token_provider.token(SCOPES).await?.as_str());
+        let res = timeout_or_cancel(req.send(), deadline, cancel).await??;
+
+        if !res.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "failed to start resumable upload for {}: {}",
+                name,
+                res.status()
+            ));
+        }
+
+        // Own the header first, see journal.md.
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .ok_or_else(|| anyhow::anyhow!("resumable upload response had no Location header"))?
+            .to_owned();
+
+        Ok(ResumableUpload {
+            session_uri: location.to_str()?.to_string(),
+            name: name.to_string(),
+            total_size,
+            committed: 0,
+        })
+    }
+
+    /// Asks the session how many bytes it has with `Content-Range: bytes */TOTAL`.
+    pub async fn resumable_upload_status(
+        &self,
+        session: &ResumableUpload,
+        cancel: &CancellationToken,
+    ) -> Result<UploadStatus> {
+        let client = resumable_client()?;
+        within_timeout(query_status(&client, session), self.timeout, cancel)
+            .await
+            .map_err(ChunkError::into_inner)
+    }
+
+    /// Uploads `byte_stream` as `name` through a resumable session, [`RESUMABLE_CHUNK_SIZE`] bytes
+    /// per `PUT`.
+    ///
+    /// The unacknowledged part of the current chunk is kept in memory, so a chunk that fails is
+    /// resent from whatever offset the session reports. A session that expires (404/410) is
+    /// restarted as long as nothing has been dropped from that buffer yet; after that, the
+    /// stream can't be rewound and the upload fails.
+    ///
+    /// The stream is hashed as it's read; the final `PUT` carries the checksums in `x-goog-hash`
+    /// so GCS refuses a corrupted object, and they're checked against the object it returns.
+    ///
+    /// Every request gets the bucket's timeout; a chunk that runs out of it is retried. `cancel`
+    /// stops the upload wherever it is, leaving the session open.
+    pub async fn upload_resumable(
+        &self,
+        byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+        fs_size: Option<usize>,
+        name: &str,
+        cancel: &CancellationToken,
+    ) -> Result<GCSObject> {
+        let mut session = self
+            .start_resumable_upload(name, fs_size.map(|s| s as u64), cancel)
+            .await?;
+        let hasher = Hasher::new(self.upload_md5);
+        self.drive_resumable_upload(
+            &mut session,
+            byte_stream,
+            hasher,
+            |_| Box::pin(async { Ok(()) }),
+            cancel,
+        )
+        .await
+    }
+
+    /// Uploads the file at `source_path` through a resumable session, recording the session in
+    /// `session_store` (when there is one) so [`GCSBucket::resume_uploads`] can finish it after a
+    /// restart, including one cut short by `cancel`.
+    pub async fn upload_file_resumable(
+        &self,
+        source_path: &Path,
+        name: &str,
+        cancel: &CancellationToken,
+    ) -> Result<GCSObject> {
+        let source_file = tokio::fs::File::open(source_path).await?;
+        let fs_size = source_file.metadata().await?.len();
+        let mut session = self
+            .start_resumable_upload(name, Some(fs_size), cancel)
+            .await?;
+
+        let store = self.session_store.as_ref();
+        record_session(store, &session, source_path).await?;
+        let reader = ReaderStream::with_capacity(source_file, READ_BUFFER_SIZE);
+        let hasher = Hasher::new(self.upload_md5);
+        let object = self
+            .drive_resumable_upload(
+                &mut session,
+                reader,
+                hasher,
+                |session| {
+                    Box::pin(async move { record_session(store, &session, source_path).await })
+                },
+                cancel,
+            )
+            .await?;
+        forget_session(store, name).await?;
+        Ok(object)
+    }
+
+    /// Picks up every session in `session_store`: asks GCS how far each one got and uploads the
+    /// rest of its source file from the last acknowledged byte. Sessions that expired are started
+    /// over from the beginning of the file.
+    ///
+    /// Returns the names of the objects that were finished.
+    pub async fn resume_uploads(&self, cancel: &CancellationToken) -> Result<Vec<String>> {
+        let store = self
+            .session_store
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no session store configured"))?;
+
+        let mut finished = Vec::new();
+        let mut failed = Vec::new();
+        for stored in store.sessions().await {
+            let name = stored.session.name.clone();
+            if cancel.is_cancelled() {
+                return Err(TimeoutOrCancel::Cancel.into());
+            }
+            match self.resume_upload(stored, cancel).await {
+                Ok(()) => finished.push(name),
+                Err(e) => failed.push(format!("{}: {:#}", name, e)),
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "failed to resume {} upload(s): {}",
+                failed.len(),
+                failed.join("; ")
+            ));
+        }
+        Ok(finished)
+    }
+
+    async fn resume_upload(&self, stored: StoredSession, cancel: &CancellationToken) -> Result<()> {
+        let requests = GcsSessionRequests {
+            bucket: self,
+            client: resumable_client()?,
+            cancel,
+        };
+        resume_session(
+            &requests,
+            self.session_store.as_ref(),
+            stored,
+            self.upload_md5,
+            RESUMABLE_CHUNK_SIZE,
+            cancel,
+        )
+        .await
+    }
+
+    /// Sends `byte_stream`, which starts at `session.committed`, through the session.
+    /// `hasher` has to have seen the bytes before `session.committed` already.
+    /// `on_commit` is called with the session whenever GCS acknowledges more bytes or the
+    /// session is restarted.
+    async fn drive_resumable_upload<'a>(
+        &self,
+        session: &mut ResumableUpload,
+        byte_stream: impl Stream<Item = std::io::Result<Bytes>>,
+        hasher: Hasher,
+        on_commit: impl FnMut(ResumableUpload) -> BoxFuture<'a, Result<()>>,
+        cancel: &CancellationToken,
+    ) -> Result<GCSObject> {
+        let requests = GcsSessionRequests {
+            bucket: self,
+            client: resumable_client()?,
+            cancel,
+        };
+        drive_upload(
+            &requests,
+            session,
+            byte_stream,
+            hasher,
+            RESUMABLE_CHUNK_SIZE,
+            on_commit,
+            cancel,
+        )
+        .await
+    }
+}
+
+/// The requests a resumable upload makes once it has a session, each with its timeout.
+trait SessionRequests: Sync {
+    /// `PUT`s `chunk` at `session.committed`. `total` is the size of the object if known, and
+    /// `x_goog_hash` goes with the request that finishes it.
+    fn put_chunk<'s>(
+        &'s self,
+        session: &'s ResumableUpload,
+        chunk: Bytes,
+        total: Option<u64>,
+        x_goog_hash: Option<String>,
+    ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>>;
+
+    /// Asks the session how many bytes it has.
+    fn query_status<'s>(
+        &'s self,
+        session: &'s ResumableUpload,
+    ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>>;
+
+    /// Starts a new session for `name`, in place of one that expired.
+    fn restart<'s>(
+        &'s self,
+        name: &'s str,
+        total_size: Option<u64>,
+    ) -> BoxFuture<'s, Result<ResumableUpload>>;
+
+    /// How long to wait before the `attempt`th retry.
+    fn backoff(&self, attempt: u32) -> Duration {
+        backoff(attempt)
+    }
+}
+
+struct GcsSessionRequests<'a> {
+    bucket: &'a GCSBucket,
+    client: Client,
+    cancel: &'a CancellationToken,
+}
+
+impl SessionRequests for GcsSessionRequests<'_> {
+    fn put_chunk<'s>(
+        &'s self,
+        session: &'s ResumableUpload,
+        chunk: Bytes,
+        total: Option<u64>,
+        x_goog_hash: Option<String>,
+    ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>> {
+        let put = put_chunk(&self.client, session, chunk, total, x_goog_hash);
+        Box::pin(within_timeout(put, self.bucket.timeout, self.cancel))
+    }
+
+    fn query_status<'s>(
+        &'s self,
+        session: &'s ResumableUpload,
+    ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>> {
+        let query = query_status(&self.client, session);
+        Box::pin(within_timeout(query, self.bucket.timeout, self.cancel))
+    }
+
+    fn restart<'s>(
+        &'s self,
+        name: &'s str,
+        total_size: Option<u64>,
+    ) -> BoxFuture<'s, Result<ResumableUpload>> {
+        Box::pin(
+            self.bucket
+                .start_resumable_upload(name, total_size, self.cancel),
+        )
+    }
+}
+
+/// [`GCSBucket::resume_upload`] through `requests`, keeping `store` up to date.
+async fn resume_session(
+    requests: &impl SessionRequests,
+    store: Option<&SessionStore>,
+    stored: StoredSession,
+    with_md5: bool,
+    chunk_size: usize,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let StoredSession {
+        mut session,
+        source_path,
+    } = stored;
+
+    let mut source_file = tokio::fs::File::open(&source_path).await?;
+    let fs_size = source_file.metadata().await?.len();
+    if session.total_size.is_some_and(|total| total != fs_size) {
+        forget_session(store, &session.name).await?;
+        return Err(anyhow::anyhow!(
+            "{} changed size since its upload started",
+            source_path.display()
+        ));
+    }
+
+    let status = requests.query_status(&session).await;
+    match status.map_err(ChunkError::into_inner)? {
+        UploadStatus::Complete(object) => {
+            // Finished before we heard back; check it against the whole file.
+            let hasher = hash_file_prefix(&mut source_file, fs_size, with_md5).await?;
+            verify_upload(&object, &hasher)?;
+            return forget_session(store, &session.name).await;
+        }
+        UploadStatus::Incomplete { committed } => session.committed = committed,
+        UploadStatus::Expired => {
+            session = requests.restart(&session.name, Some(fs_size)).await?;
+        }
+    }
+    record_session(store, &session, &source_path).await?;
+
+    // The checksums sent at the end cover the whole object, so catch up on what GCS
+    // already has.
+    let hasher = hash_file_prefix(&mut source_file, session.committed, with_md5).await?;
+    source_file
+        .seek(std::io::SeekFrom::Start(session.committed))
+        .await?;
+    let reader = ReaderStream::with_capacity(source_file, READ_BUFFER_SIZE);
+    let source_path = source_path.as_path();
+    drive_upload(
+        requests,
+        &mut session,
+        reader,
+        hasher,
+        chunk_size,
+        |session| Box::pin(async move { record_session(store, &session, source_path).await }),
+        cancel,
+    )
+    .await?;
+    forget_session(store, &session.name).await
+}
+
+async fn record_session(
+    store: Option<&SessionStore>,
+    session: &ResumableUpload,
+    source_path: &Path,
+) -> Result<()> {
+    match store {
+        Some(store) => {
+            store
+                .put(StoredSession {
+                    session: session.clone(),
+                    source_path: source_path.to_path_buf(),
+                })
+                .await
+        }
+        None => Ok(()),
+    }
+}
+
+async fn forget_session(store: Option<&SessionStore>, name: &str) -> Result<()> {
+    match store {
+        Some(store) => store.remove(name).await,
+        None => Ok(()),
+    }
+}
+
+/// [`GCSBucket::drive_resumable_upload`] through `requests`, `chunk_size` bytes per `PUT`.
+async fn drive_upload<'a>(
+    requests: &impl SessionRequests,
+    session: &mut ResumableUpload,
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>>,
+    mut hasher: Hasher,
+    chunk_size: usize,
+    mut on_commit: impl FnMut(ResumableUpload) -> BoxFuture<'a, Result<()>>,
+    cancel: &CancellationToken,
+) -> Result<GCSObject> {
+    let name = session.name.clone();
+    let total_size = session.total_size;
+
+    let mut byte_stream = std::pin::pin!(byte_stream);
+    // Everything from `session.committed` onwards that GCS hasn't acknowledged.
+    let mut buffer = BytesMut::new();
+    let mut exhausted = false;
+    let mut attempts = 0;
+
+    loop {
+        while !exhausted && buffer.len() < chunk_size {
+            match byte_stream.next().await {
+                Some(bytes) => {
+                    let bytes = bytes?;
+                    hasher.update(&bytes);
+                    buffer.extend_from_slice(&bytes);
+                }
+                None => exhausted = true,
+            }
+        }
+
+        let len = if exhausted { buffer.len() } else { chunk_size };
+        // Once the stream is done, what we have is the size, whatever we were told.
+        let total = if exhausted {
+            Some(session.committed + len as u64)
+        } else {
+            total_size
+        };
+        let chunk = Bytes::copy_from_slice(&buffer[..len]);
+        // Only the request that finishes the object can carry its checksums, and by then
+        // everything has gone through `hasher`.
+        let x_goog_hash = (total == Some(session.committed + len as u64))
+            .then(|| hasher.checksums().x_goog_hash());
+
+        // Whether the chunk failed, and this attempt has been counted and waited for already.
+        let mut retried = false;
+        let status = match requests.put_chunk(session, chunk, total, x_goog_hash).await {
+            Ok(status) => status,
+            Err(ChunkError::Fatal(e)) => return Err(e),
+            Err(ChunkError::Retryable(e)) => {
+                attempts += 1;
+                if attempts > MAX_RETRIES {
+                    return Err(e.context(format!(
+                        "resumable upload of {} failed after {} retries",
+                        name, MAX_RETRIES
+                    )));
+                }
+                sleep_or_cancel(requests.backoff(attempts), cancel).await?;
+                retried = true;
+
+                match requests.query_status(session).await {
+                    Ok(status) => status,
+                    Err(ChunkError::Fatal(e)) => return Err(e),
+                    // Try the chunk again, it'll come back here if it's still down.
+                    Err(ChunkError::Retryable(_)) => continue,
+                }
+            }
+        };
+
+        match status {
+            UploadStatus::Complete(object) => {
+                verify_upload(&object, &hasher)?;
+                return Ok(*object);
+            }
+            UploadStatus::Incomplete { committed } => {
+                let acked = committed
+                    .checked_sub(session.committed)
+                    .filter(|acked| *acked <= len as u64)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "GCS acknowledged {} bytes of {}, but {} were sent",
+                            committed,
+                            name,
+                            session.committed + len as u64
+                        )
+                    })?;
+                if acked > 0 {
+                    attempts = 0;
+                } else if len > 0 && !retried {
+                    // Took the chunk but kept none of it.
+                    attempts += 1;
+                    if attempts > MAX_RETRIES {
+                        return Err(anyhow::anyhow!(
+                            "resumable upload of {} made no progress after {} retries",
+                            name,
+                            MAX_RETRIES
+                        ));
+                    }
+                    sleep_or_cancel(requests.backoff(attempts), cancel).await?;
+                }
+                buffer.advance(acked as usize);
+                session.committed = committed;
+                on_commit(session.clone()).await?;
+            }
+            UploadStatus::Expired if session.committed == 0 => {
+                *session = requests.restart(&name, total_size).await?;
+                on_commit(session.clone()).await?;
+            }
+            UploadStatus::Expired => {
+                return Err(anyhow::anyhow!(
+                    "resumable session for {} expired after {} bytes, the upload has to be restarted from the source",
+                    name,
+                    session.committed
+                ));
+            }
+        }
+    }
+}
+
+// GCS answers an incomplete upload with `308 Resume Incomplete`, which reqwest would otherwise
+// treat as a redirect.
+fn resumable_client() -> Result<Client> {
+    Ok(Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?)
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt.min(6)))
+}
+
+async fn sleep_or_cancel(duration: Duration, cancel: &CancellationToken) -> Result<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        _ = cancel.cancelled() => Err(TimeoutOrCancel::Cancel.into()),
+    }
+}
+
+/// A request that runs out of `timeout` is retried like a dropped connection; cancellation stops
+/// the upload.
+async fn within_timeout(
+    request: impl std::future::Future<Output = Result<UploadStatus, ChunkError>>,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<UploadStatus, ChunkError> {
+    match timeout_or_cancel(request, Instant::now() + timeout, cancel).await {
+        Ok(status) => status,
+        Err(e @ TimeoutOrCancel::Timeout) => Err(ChunkError::Retryable(e.into())),
+        Err(e @ TimeoutOrCancel::Cancel) => Err(ChunkError::Fatal(e.into())),
+    }
+}
+
+async fn put_chunk(
+    client: &Client,
+    session: &ResumableUpload,
+    chunk: Bytes,
+    total: Option<u64>,
+    x_goog_hash: Option<String>,
+) -> Result<UploadStatus, ChunkError> {
+    let total_str = total.map_or("*".to_string(), |t| t.to_string());
+    let content_range = if chunk.is_empty() {
+        format!("bytes */{}", total_str)
+    } else {
+        format!(
+            "bytes {}-{}/{}",
+            session.committed,
+            session.committed + chunk.len() as u64 - 1,
+            total_str
+        )
+    };
+
+    let mut req = client
+        .put(&session.session_uri)
+        .header(header::CONTENT_LENGTH, chunk.len())
+        .header(header::CONTENT_RANGE, content_range);
+    if let Some(x_goog_hash) = x_goog_hash {
+        req = req.header("x-goog-hash", x_goog_hash);
+    }
+
+    let res = req
+        .body(chunk)
+        .send()
+        .await
+        .map_err(|e| ChunkError::Retryable(e.into()))?;
+
+    session_status(res).await
+}
+
+async fn query_status(
+    client: &Client,
+    session: &ResumableUpload,
+) -> Result<UploadStatus, ChunkError> {
+    let total_str = session
+        .total_size
+        .map_or("*".to_string(), |t| t.to_string());
+
+    let res = client
+        .put(&session.session_uri)
+        .header(header::CONTENT_LENGTH, 0)
+        .header(header::CONTENT_RANGE, format!("bytes */{}", total_str))
+        .send()
+        .await
+        .map_err(|e| ChunkError::Retryable(e.into()))?;
+
+    session_status(res).await
+}
+
+async fn session_status(res: reqwest::Response) -> Result<UploadStatus, ChunkError> {
+    match res.status() {
+        StatusCode::OK | StatusCode::CREATED => {
+            let body = res
+                .text()
+                .await
+                .map_err(|e| ChunkError::Retryable(e.into()))?;
+            let object = serde_json::from_str(&body).map_err(|e| ChunkError::Fatal(e.into()))?;
+            Ok(UploadStatus::Complete(Box::new(object)))
+        }
+        StatusCode::PERMANENT_REDIRECT => {
+            // No `Range` header means nothing was persisted: start from the beginning.
+            let committed = match res.headers().get(header::RANGE) {
+                Some(range) => committed_from_range(range.to_str().unwrap_or_default())
+                    .ok_or_else(|| {
+                        ChunkError::Fatal(anyhow::anyhow!("unexpected Range header: {:?}", range))
+                    })?,
+                None => 0,
+            };
+            Ok(UploadStatus::Incomplete { committed })
+        }
+        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(UploadStatus::Expired),
+        status if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => Err(
+            ChunkError::Retryable(anyhow::anyhow!("resumable upload returned {}", status)),
+        ),
+        status => {
+            let body = res.text().await.unwrap_or_default();
+            Err(ChunkError::Fatal(
+                UploadError::from_response(status, &body).into(),
+            ))
+        }
+    }
+}
+
+fn verify_upload(object: &GCSObject, hasher: &Hasher) -> Result<()> {
+    hasher
+        .checksums()
+        .verify(object)
+        .map_err(|why| UploadError::ChecksumMismatch(why).into())
+}
+
+/// Hashes the first `len` bytes of `file`, leaving it positioned after them.
+async fn hash_file_prefix(file: &mut tokio::fs::File, len: u64, with_md5: bool) -> Result<Hasher> {
+    let mut hasher = Hasher::new(with_md5);
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    let mut remaining = len;
+
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    while remaining > 0 {
+        let want = buf.len().min(remaining as usize);
+        let n = file.read(&mut buf[..want]).await?;
+        if n == 0 {
+            return Err(anyhow::anyhow!(
+                "file ended {} bytes short of {}",
+                remaining,
+                len
+            ));
+        }
+        hasher.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(hasher)
+}
+
+/// `Range: bytes=0-42` means bytes 0 through 42 are persisted, so 43 are committed.
+fn committed_from_range(range: &str) -> Option<u64> {
+    range
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(_, last)| last.trim().parse::<u64>().ok())
+        .map(|last| last + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    const DATA: &[u8] = b"abcdefghij";
+
+    /// How [`FakeSession`] answers a request.
+    #[derive(Debug, Clone, Copy)]
+    enum Reply {
+        /// Keeps at most this much of a `PUT` and reports where the upload is.
+        Keep(usize),
+        Fail,
+        Expire,
+    }
+
+    /// A session that stores what it keeps of each `PUT` and answers from scripts, keeping
+    /// everything and reporting progress once a script runs out.
+    #[derive(Default)]
+    struct FakeSession {
+        stored: Mutex<Vec<u8>>,
+        puts: Mutex<VecDeque<Reply>>,
+        queries: Mutex<VecDeque<Reply>>,
+        /// `(offset, len, total, x_goog_hash)` of every `PUT`, and `query`/`restart` marks.
+        log: Mutex<Vec<String>>,
+    }
+
+    impl FakeSession {
+        fn new(puts: &[Reply], queries: &[Reply]) -> Self {
+            Self {
+                puts: Mutex::new(puts.iter().copied().collect()),
+                queries: Mutex::new(queries.iter().copied().collect()),
+                ..Default::default()
+            }
+        }
+
+        fn answer(&self, reply: Reply, total: Option<u64>) -> Result<UploadStatus, ChunkError> {
+            let stored = self.stored.lock().unwrap();
+            match reply {
+                Reply::Keep(_) if total == Some(stored.len() as u64) => {
+                    let mut hasher = Hasher::new(false);
+                    hasher.update(&stored);
+                    let object = serde_json::json!({
+                        "name": "a.tif",
+                        "crc32c": hasher.checksums().crc32c_base64(),
+                    });
+                    let object = serde_json::from_value(object).unwrap();
+                    Ok(UploadStatus::Complete(Box::new(object)))
+                }
+                Reply::Keep(_) => Ok(UploadStatus::Incomplete {
+                    committed: stored.len() as u64,
+                }),
+                Reply::Fail => Err(ChunkError::Retryable(anyhow::anyhow!("503"))),
+                Reply::Expire => Ok(UploadStatus::Expired),
+            }
+        }
+
+        fn log(&self) -> Vec<String> {
+            self.log.lock().unwrap().clone()
+        }
+    }
+
+    impl SessionRequests for FakeSession {
+        fn put_chunk<'s>(
+            &'s self,
+            session: &'s ResumableUpload,
+            chunk: Bytes,
+            total: Option<u64>,
+            x_goog_hash: Option<String>,
+        ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>> {
+            self.log.lock().unwrap().push(format!(
+                "{} {} {:?} {}",
+                session.committed,
+                chunk.len(),
+                total,
+                x_goog_hash.is_some()
+            ));
+            let reply = self.puts.lock().unwrap().pop_front();
+            let reply = reply.unwrap_or(Reply::Keep(usize::MAX));
+            if let Reply::Keep(keep) = reply {
+                let mut stored = self.stored.lock().unwrap();
+                assert_eq!(stored.len() as u64, session.committed);
+                stored.extend_from_slice(&chunk[..keep.min(chunk.len())]);
+            }
+            let status = self.answer(reply, total);
+            Box::pin(async move { status })
+        }
+
+        fn query_status<'s>(
+            &'s self,
+            session: &'s ResumableUpload,
+        ) -> BoxFuture<'s, Result<UploadStatus, ChunkError>> {
+            self.log.lock().unwrap().push("query".to_string());
+            let reply = self.queries.lock().unwrap().pop_front();
+            let status = self.answer(reply.unwrap_or(Reply::Keep(0)), session.total_size);
+            Box::pin(async move { status })
+        }
+
+        fn restart<'s>(
+            &'s self,
+            name: &'s str,
+            total_size: Option<u64>,
+        ) -> BoxFuture<'s, Result<ResumableUpload>> {
+            self.log.lock().unwrap().push("restart".to_string());
+            self.stored.lock().unwrap().clear();
+            let session = ResumableUpload {
+                session_uri: "restarted".to_string(),
+                name: name.to_string(),
+                total_size,
+                committed: 0,
+            };
+            Box::pin(async move { Ok(session) })
+        }
+
+        fn backoff(&self, _attempt: u32) -> Duration {
+            Duration::ZERO
+        }
+    }
+
+    /// Uploads [`DATA`] through `requests` in chunks of 4, returning the `committed` of every
+    /// session recorded along the way.
+    async fn fake_upload(
+        requests: &FakeSession,
+        total_size: Option<u64>,
+    ) -> (Result<GCSObject>, Vec<(String, u64)>) {
+        let mut session = ResumableUpload {
+            session_uri: "first".to_string(),
+            name: "a.tif".to_string(),
+            total_size,
+            committed: 0,
+        };
+        let byte_stream = futures::stream::iter(
+            DATA.chunks(3)
+                .map(|c| Ok(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        );
+        let commits = Mutex::new(Vec::new());
+        let on_commit = |session: ResumableUpload| -> BoxFuture<'_, Result<()>> {
+            commits
+                .lock()
+                .unwrap()
+                .push((session.session_uri, session.committed));
+            Box::pin(async { Ok(()) })
+        };
+        let cancel = CancellationToken::new();
+        let hasher = Hasher::new(false);
+        let result = drive_upload(
+            requests,
+            &mut session,
+            byte_stream,
+            hasher,
+            4,
+            on_commit,
+            &cancel,
+        )
+        .await;
+        (result, commits.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn partial_acks_are_sent_again() {
+        let requests = FakeSession::new(&[Reply::Keep(2)], &[]);
+        let (object, commits) = fake_upload(&requests, Some(10)).await;
+        assert_eq!("a.tif", object.unwrap().name);
+        assert_eq!(DATA, &requests.stored.lock().unwrap()[..]);
+        // Only the request that finishes the object carries the checksums.
+        assert_eq!(
+            vec![
+                "0 4 Some(10) false",
+                "2 4 Some(10) false",
+                "6 4 Some(10) true"
+            ],
+            requests.log()
+        );
+        let first = "first".to_string();
+        assert_eq!(vec![(first.clone(), 2), (first, 6)], commits);
+
+        // Without a size, the end of the stream is only known after the last full chunk.
+        let requests = FakeSession::new(&[], &[]);
+        let (object, _) = fake_upload(&requests, None).await;
+        object.unwrap();
+        assert_eq!(
+            vec!["0 4 None false", "4 4 None false", "8 2 Some(10) true",],
+            requests.log()
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_session_restarts_only_before_anything_is_committed() {
+        let requests = FakeSession::new(&[Reply::Expire], &[]);
+        let (object, commits) = fake_upload(&requests, Some(10)).await;
+        object.unwrap();
+        assert_eq!("restart", requests.log()[1]);
+        assert_eq!(("restarted".to_string(), 0), commits[0]);
+        assert_eq!(DATA, &requests.stored.lock().unwrap()[..]);
+
+        let requests = FakeSession::new(&[Reply::Keep(4), Reply::Expire], &[]);
+        let (object, _) = fake_upload(&requests, Some(10)).await;
+        let err = object.unwrap_err().to_string();
+        assert!(err.contains("expired after 4 bytes"), "{}", err);
+        assert!(!requests.log().contains(&"restart".to_string()));
+    }
+
+    #[tokio::test]
+    async fn failed_chunks_are_retried_up_to_the_limit() {
+        // Down for a moment: the status query fails too, then the chunk goes through.
+        let requests = FakeSession::new(&[Reply::Fail], &[Reply::Fail]);
+        let (object, _) = fake_upload(&requests, Some(10)).await;
+        object.unwrap();
+        assert_eq!(
+            vec!["0 4 Some(10) false", "query", "0 4 Some(10) false"],
+            requests.log()[..3]
+        );
+
+        // Every failed chunk followed by a status with no progress is one attempt, not two.
+        let requests = FakeSession::new(&[Reply::Fail; 10], &[Reply::Keep(0); 10]);
+        let (object, _) = fake_upload(&requests, Some(10)).await;
+        let err = format!("{:#}", object.unwrap_err());
+        assert!(err.contains("failed after 5 retries"), "{}", err);
+        let log = requests.log();
+        let puts = log.iter().filter(|line| *line != "query").count();
+        assert_eq!(MAX_RETRIES as usize + 1, puts);
+        assert_eq!(MAX_RETRIES as usize, log.len() - puts);
+    }
+
+    #[tokio::test]
+    async fn chunks_kept_in_none_count_as_attempts() {
+        let requests = FakeSession::new(&[Reply::Keep(0); 10], &[]);
+        let (object, _) = fake_upload(&requests, Some(10)).await;
+        let err = object.unwrap_err().to_string();
+        assert!(err.contains("made no progress after 5 retries"), "{}", err);
+        assert_eq!(MAX_RETRIES as usize + 1, requests.log().len());
+    }
+
+    /// Resumes `session` of a file holding [`DATA`] through `requests`, in chunks of 4, with
+    /// the session kept in a store. Returns the result and whether the store still has it.
+    async fn fake_resume(requests: &FakeSession, session: ResumableUpload) -> (Result<()>, bool) {
+        let id = uuid::Uuid::new_v4();
+        let source_path = std::env::temp_dir().join(format!("gcs-rs-resume-{}.tif", id));
+        let store_path = std::env::temp_dir().join(format!("gcs-rs-resume-{}.json", id));
+        std::fs::write(&source_path, DATA).unwrap();
+        let store = SessionStore::open(&store_path).unwrap();
+        let stored = StoredSession {
+            session,
+            source_path: source_path.clone(),
+        };
+        store.put(stored.clone()).await.unwrap();
+
+        let cancel = CancellationToken::new();
+        let result = resume_session(requests, Some(&store), stored, false, 4, &cancel).await;
+        let kept = !store.sessions().await.is_empty();
+
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&store_path).unwrap();
+        (result, kept)
+    }
+
+    fn stored_session(total_size: u64) -> ResumableUpload {
+        ResumableUpload {
+            session_uri: "first".to_string(),
+            name: "a.tif".to_string(),
+            total_size: Some(total_size),
+            committed: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn uploads_finished_before_the_ack_are_checked_against_the_file() {
+        let requests = FakeSession::new(&[], &[]);
+        requests.stored.lock().unwrap().extend_from_slice(DATA);
+        let (result, kept) = fake_resume(&requests, stored_session(10)).await;
+        result.unwrap();
+        assert!(!kept);
+        assert_eq!(vec!["query"], requests.log());
+
+        let requests = FakeSession::new(&[], &[]);
+        requests
+            .stored
+            .lock()
+            .unwrap()
+            .extend_from_slice(b"abcdefghiX");
+        let (result, _) = fake_resume(&requests, stored_session(10)).await;
+        let err = result.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<UploadError>(),
+                Some(UploadError::ChecksumMismatch(_))
+            ),
+            "{:#}",
+            err
+        );
+        assert_eq!(vec!["query"], requests.log());
+    }
+
+    #[tokio::test]
+    async fn resuming_hashes_what_was_committed_before_carrying_on() {
+        // The store is behind: GCS has 4 bytes the last record didn't hear about.
+        let requests = FakeSession::new(&[], &[]);
+        requests
+            .stored
+            .lock()
+            .unwrap()
+            .extend_from_slice(&DATA[..4]);
+        let (result, kept) = fake_resume(&requests, stored_session(10)).await;
+        // The final checksums only match if the first 4 bytes were hashed from the file.
+        result.unwrap();
+        assert!(!kept);
+        assert_eq!(DATA, &requests.stored.lock().unwrap()[..]);
+        assert_eq!(
+            vec!["query", "4 4 Some(10) false", "8 2 Some(10) true"],
+            requests.log()
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_resumed_from_the_start() {
+        let requests = FakeSession::new(&[], &[Reply::Expire]);
+        requests
+            .stored
+            .lock()
+            .unwrap()
+            .extend_from_slice(&DATA[..4]);
+        let mut session = stored_session(10);
+        session.committed = 4;
+        let (result, kept) = fake_resume(&requests, session).await;
+        result.unwrap();
+        assert!(!kept);
+        assert_eq!(DATA, &requests.stored.lock().unwrap()[..]);
+        assert_eq!(
+            vec!["query", "restart", "0 4 Some(10) false"],
+            requests.log()[..3]
+        );
+    }
+
+    #[tokio::test]
+    async fn files_that_changed_size_are_given_up_on() {
+        let requests = FakeSession::new(&[], &[]);
+        let (result, kept) = fake_resume(&requests, stored_session(11)).await;
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("changed size"), "{}", err);
+        assert!(!kept);
+        assert!(requests.log().is_empty());
+    }
+
+    #[test]
+    fn range_header_is_last_byte_inclusive() {
+        assert_eq!(Some(43), committed_from_range("bytes=0-42"));
+        assert_eq!(Some(1), committed_from_range("bytes=0-0"));
+        assert_eq!(None, committed_from_range("0-42"));
+        assert_eq!(None, committed_from_range("bytes=0-"));
+    }
+
+    #[test]
+    fn chunk_size_is_aligned() {
+        assert_eq!(0, RESUMABLE_CHUNK_SIZE % CHUNK_ALIGNMENT);
+    }
+}
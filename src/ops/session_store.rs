@@ -0,0 +1,151 @@
+use crate::ops::resumable::ResumableUpload;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// A resumable upload session and the file it's uploading.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredSession {
+    pub session: ResumableUpload,
+    pub source_path: PathBuf,
+}
+
+/// Resumable upload sessions kept in a local JSON state file, keyed by object name, so they
+/// outlive the process. GCS keeps a session alive for a week.
+///
+/// Every change is written straight through to disk, one write at a time.
+#[derive(Debug)]
+pub struct SessionStore {
+    path: PathBuf,
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl SessionStore {
+    /// Loads the state file at `path`, or starts empty if there isn't one yet.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let sessions = match std::fs::read_to_string(&path) {
+            Ok(body) => serde_json::from_str::<Vec<StoredSession>>(&body)?
+                .into_iter()
+                .map(|stored| (stored.session.name.clone(), stored))
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path,
+            sessions: Mutex::new(sessions),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn sessions(&self) -> Vec<StoredSession> {
+        self.sessions.lock().await.values().cloned().collect()
+    }
+
+    /// Records `stored`, replacing whatever was kept for the same object name.
+    pub async fn put(&self, stored: StoredSession) -> Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        sessions.insert(stored.session.name.clone(), stored);
+        self.save(&sessions).await
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<()> {
+        let mut sessions = self.sessions.lock().await;
+        if sessions.remove(name).is_some() {
+            self.save(&sessions).await?;
+        }
+        Ok(())
+    }
+
+    // Write a sibling file and rename it over the old one, so a crash mid-write can't leave
+    // half a state file behind. The lock on `sessions` is held throughout, so an older state
+    // can't be renamed over a newer one.
+    async fn save(&self, sessions: &HashMap<String, StoredSession>) -> Result<()> {
+        let body = serde_json::to_string_pretty(&sessions.values().collect::<Vec<_>>())?;
+        // `a.json.tmp`, never the state file itself, whatever it's called.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut tmp = tokio::fs::File::create(&tmp_path).await?;
+        tmp.write_all(body.as_bytes()).await?;
+        // On disk before the rename, or a crash could leave the new name on an empty file.
+        tmp.sync_all().await?;
+        drop(tmp);
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        // The rename is only durable once the directory holding it is.
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            tokio::fs::File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn sessions_survive_reopening_the_store() {
+        let path = std::env::temp_dir().join(format!("gcs-rs-sessions-{}.json", Uuid::new_v4()));
+        let stored = StoredSession {
+            session: ResumableUpload {
+                session_uri: "https://storage.googleapis.com/upload/storage/v1/b/bucket/o?uploadType=resumable&upload_id=abc".to_string(),
+                name: "box/tiff/2023/TN/a.tif".to_string(),
+                total_size: Some(20_000_000),
+                committed: 43,
+            },
+            source_path: PathBuf::from("/tmp/a.tif"),
+        };
+
+        let store = SessionStore::open(&path).unwrap();
+        assert!(store.sessions().await.is_empty());
+        store.put(stored.clone()).await.unwrap();
+
+        let reopened = SessionStore::open(&path).unwrap();
+        assert_eq!(vec![stored.clone()], reopened.sessions().await);
+
+        reopened.remove(&stored.session.name).await.unwrap();
+        let emptied = SessionStore::open(&path).unwrap();
+        assert!(emptied.sessions().await.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn state_files_named_tmp_are_saved_through_another_file() {
+        let path = std::env::temp_dir().join(format!("gcs-rs-sessions-{}.tmp", Uuid::new_v4()));
+        let stored = StoredSession {
+            session: ResumableUpload {
+                session_uri: "https://storage.googleapis.com/upload/storage/v1/b/bucket/o?uploadType=resumable&upload_id=abc".to_string(),
+                name: "a.tif".to_string(),
+                total_size: None,
+                committed: 0,
+            },
+            source_path: PathBuf::from("/tmp/a.tif"),
+        };
+
+        let store = SessionStore::open(&path).unwrap();
+        store.put(stored.clone()).await.unwrap();
+        assert!(!path.with_extension("tmp.tmp").exists());
+        assert_eq!(
+            vec![stored],
+            SessionStore::open(&path).unwrap().sessions().await
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,288 @@
+use crate::ops::gcs_bucket::{GCSBucket, ListOpts};
+use crate::ops::types::{DownloadError, Listing, ListingMode};
+use futures::future::{BoxFuture, Either};
+use futures::stream::{self, BoxStream, Stream};
+use futures_util::{StreamExt, TryStreamExt};
+use std::num::NonZeroUsize;
+use std::pin::pin;
+use tokio_util::sync::CancellationToken;
+
+/// Where [`GCSBucket::list_sharded`] splits a prefix into key ranges to list side by side.
+#[derive(Debug, Clone)]
+pub enum Sharding {
+    /// At the "directories" one level below the prefix, found with a delimiter listing first.
+    SubPrefixes,
+    /// At these keys. Each range runs from one of them up to the next.
+    SplitPoints(Vec<String>),
+}
+
+impl GCSBucket {
+    /// Lists everything under `prefix`, without a delimiter, as `concurrency` listings of key
+    /// ranges running at once. A single listing can't go faster than one page after another,
+    /// since each page needs the `nextPageToken` of the one before.
+    ///
+    /// The ranges come from `sharding` and are listed with `startOffset`/`endOffset` within
+    /// those of `opts`. The pages come out as they arrive, or with `ordered` in key order: a
+    /// range is then held in memory until the ranges before it are done.
+    pub fn list_sharded<'a>(
+        &'a self,
+        prefix: Option<String>,
+        sharding: Sharding,
+        concurrency: NonZeroUsize,
+        ordered: bool,
+        opts: &ListOpts,
+        cancel: &CancellationToken,
+    ) -> impl Stream<Item = Result<Listing, DownloadError>> + Send + 'a {
+        let cancel = cancel.clone();
+        sharded_listing(
+            sharding,
+            concurrency,
+            ordered,
+            opts.clone(),
+            move |mode, opts| {
+                self.list_streaming_with(prefix.clone(), mode, None, &opts, &cancel)
+                    .boxed()
+            },
+        )
+    }
+}
+
+/// [`GCSBucket::list_sharded`], with `list` listing the prefix with the mode and options given.
+fn sharded_listing<'a>(
+    sharding: Sharding,
+    concurrency: NonZeroUsize,
+    ordered: bool,
+    opts: ListOpts,
+    list: impl Fn(ListingMode, ListOpts) -> BoxStream<'a, Result<Listing, DownloadError>>
+        + Send
+        + Sync
+        + 'a,
+) -> impl Stream<Item = Result<Listing, DownloadError>> + Send + 'a {
+    async_stream::stream! {
+        let split_points = match sharding {
+            Sharding::SplitPoints(split_points) => split_points,
+            Sharding::SubPrefixes => {
+                let bounds = ListOpts {
+                    start_offset: opts.start_offset.clone(),
+                    end_offset: opts.end_offset.clone(),
+                    ..Default::default()
+                };
+                let mut discovery = list(ListingMode::WithDelimiter, bounds);
+                let mut prefixes = Vec::new();
+                while let Some(page) = discovery.next().await {
+                    prefixes.extend(page?.prefixes);
+                }
+                prefixes
+            }
+        };
+
+        let list_shard = |shard: ListOpts| list(ListingMode::NoDelimiter, shard);
+        let shards = stream::iter(shards(split_points, &opts));
+        let mut pages = pin!(if ordered {
+            Either::Left(
+                shards
+                    .map(|shard| -> BoxFuture<'a, Result<Vec<Listing>, DownloadError>> {
+                        Box::pin(list_shard(shard).try_collect())
+                    })
+                    .buffered(concurrency.get())
+                    .flat_map(|pages| match pages {
+                        Ok(pages) => Either::Left(stream::iter(pages.into_iter().map(Ok))),
+                        Err(e) => Either::Right(stream::once(async { Err(e) })),
+                    }),
+            )
+        } else {
+            Either::Right(shards.map(list_shard).flatten_unordered(concurrency.get()))
+        });
+        while let Some(page) = pages.next().await {
+            yield page;
+        }
+    }
+}
+
+/// `opts` once per key range between consecutive `split_points`, in key order, covering
+/// exactly its `startOffset..endOffset`.
+fn shards(mut split_points: Vec<String>, opts: &ListOpts) -> Vec<ListOpts> {
+    split_points.sort();
+    split_points.dedup();
+    split_points.retain(|point| {
+        opts.start_offset.as_ref().is_none_or(|start| point > start)
+            && opts.end_offset.as_ref().is_none_or(|end| point < end)
+    });
+
+    let starts: Vec<_> = std::iter::once(opts.start_offset.clone())
+        .chain(split_points.iter().cloned().map(Some))
+        .collect();
+    let ends = split_points
+        .into_iter()
+        .map(Some)
+        .chain(std::iter::once(opts.end_offset.clone()));
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(|(start_offset, end_offset)| ListOpts {
+            start_offset,
+            end_offset,
+            ..opts.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::types::ListingObject;
+    use std::time::SystemTime;
+
+    const KEYS: &[&str] = &[
+        "a/1", "a/2", "a/3", "b/1", "b/2", "c", "d/1", "d/2", "d/3", "d/4", "e/1",
+    ];
+
+    /// Lists [`KEYS`] within the offsets of the options the way GCS would, two to a page. The
+    /// listing of the range starting at `fail_at` fails after its first page.
+    fn fake_list(
+        fail_at: Option<&'static str>,
+    ) -> impl Fn(ListingMode, ListOpts) -> BoxStream<'static, Result<Listing, DownloadError>> + Send + Sync
+    {
+        move |mode, opts| {
+            let in_range = KEYS.iter().filter(|key| {
+                opts.start_offset
+                    .as_deref()
+                    .is_none_or(|start| **key >= start)
+                    && opts.end_offset.as_deref().is_none_or(|end| **key < end)
+            });
+            let mut entries: Vec<(Option<String>, Option<String>)> = Vec::new();
+            for key in in_range {
+                match key.split_once('/') {
+                    Some((dir, _)) if mode == ListingMode::WithDelimiter => {
+                        let prefix = Some(format!("{}/", dir));
+                        if entries.last().is_none_or(|(last, _)| *last != prefix) {
+                            entries.push((prefix, None));
+                        }
+                    }
+                    _ => entries.push((None, Some(key.to_string()))),
+                }
+            }
+
+            let mut pages: Vec<_> = entries
+                .chunks(2)
+                .map(|page| {
+                    Ok(Listing {
+                        prefixes: page.iter().filter_map(|(p, _)| p.clone()).collect(),
+                        keys: page
+                            .iter()
+                            .filter_map(|(_, key)| key.clone())
+                            .map(|key| ListingObject {
+                                key,
+                                last_modified: SystemTime::UNIX_EPOCH,
+                                size: 0,
+                            })
+                            .collect(),
+                    })
+                })
+                .collect();
+            if fail_at.is_some() && opts.start_offset.as_deref() == fail_at {
+                pages.truncate(1);
+                pages.push(Err(DownloadError::Other(anyhow::anyhow!("listing broke"))));
+            }
+            stream::iter(pages)
+                .then(|page| async {
+                    // Let the other shards get a page in.
+                    tokio::task::yield_now().await;
+                    page
+                })
+                .boxed()
+        }
+    }
+
+    async fn sharded_keys(
+        sharding: Sharding,
+        ordered: bool,
+        fail_at: Option<&'static str>,
+    ) -> Result<Vec<String>, DownloadError> {
+        let concurrency = NonZeroUsize::new(3).unwrap();
+        let opts = ListOpts::default();
+        let pages = sharded_listing(sharding, concurrency, ordered, opts, fake_list(fail_at));
+        let pages: Vec<Listing> = pages.try_collect().await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.keys)
+            .map(|object| object.key)
+            .collect())
+    }
+
+    fn split_points() -> Sharding {
+        let points = ["a/2", "c", "d/", "d/3", "zzz"];
+        Sharding::SplitPoints(points.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn sharded_listing_lists_every_key_once() {
+        let all: Vec<String> = KEYS.iter().map(|key| key.to_string()).collect();
+        for sharding in [Sharding::SubPrefixes, split_points()] {
+            let mut unordered = sharded_keys(sharding.clone(), false, None).await.unwrap();
+            unordered.sort();
+            assert_eq!(all, unordered, "{:?}", sharding);
+
+            let ordered = sharded_keys(sharding.clone(), true, None).await.unwrap();
+            assert_eq!(all, ordered, "{:?}", sharding);
+        }
+    }
+
+    #[tokio::test]
+    async fn sharded_listing_passes_on_a_failed_shard() {
+        for ordered in [false, true] {
+            let result = sharded_keys(Sharding::SubPrefixes, ordered, Some("b/")).await;
+            assert!(matches!(result, Err(DownloadError::Other(_))));
+            let result = sharded_keys(split_points(), ordered, Some("d/3")).await;
+            assert!(matches!(result, Err(DownloadError::Other(_))));
+        }
+    }
+
+    fn ranges(shards: &[ListOpts]) -> Vec<(Option<&str>, Option<&str>)> {
+        shards
+            .iter()
+            .map(|s| (s.start_offset.as_deref(), s.end_offset.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn shards_cover_the_range_once() {
+        let split_points = vec![
+            "2023/TN/m/".to_string(),
+            "2023/TN/c/".to_string(),
+            "2023/TN/m/".to_string(),
+        ];
+        assert_eq!(
+            vec![
+                (None, Some("2023/TN/c/")),
+                (Some("2023/TN/c/"), Some("2023/TN/m/")),
+                (Some("2023/TN/m/"), None),
+            ],
+            ranges(&shards(split_points.clone(), &ListOpts::default()))
+        );
+
+        // Split points outside the bounds, or on them, don't make empty ranges.
+        let opts = ListOpts {
+            start_offset: Some("2023/TN/c/".to_string()),
+            end_offset: Some("2023/TN/z".to_string()),
+            match_glob: Some("**/*.tif".to_string()),
+            ..Default::default()
+        };
+        let bounded = shards(split_points, &opts);
+        assert_eq!(
+            vec![
+                (Some("2023/TN/c/"), Some("2023/TN/m/")),
+                (Some("2023/TN/m/"), Some("2023/TN/z")),
+            ],
+            ranges(&bounded)
+        );
+        assert!(bounded
+            .iter()
+            .all(|s| s.match_glob.as_deref() == Some("**/*.tif")));
+
+        assert_eq!(
+            vec![(None, None)],
+            ranges(&shards(Vec::new(), &ListOpts::default()))
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::ops::types::{DownloadError, TimeoutOrCancel};
+use bytes::Bytes;
+use futures::stream::Stream;
+use futures_util::StreamExt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Runs `fut` until it finishes, `deadline` passes or `cancel` fires, whichever comes first.
+pub(crate) async fn timeout_or_cancel<T>(
+    fut: impl Future<Output = T>,
+    deadline: Instant,
+    cancel: &CancellationToken,
+) -> Result<T, TimeoutOrCancel> {
+    tokio::select! {
+        res = fut => Ok(res),
+        _ = tokio::time::sleep_until(deadline) => Err(TimeoutOrCancel::Timeout),
+        _ = cancel.cancelled() => Err(TimeoutOrCancel::Cancel),
+    }
+}
+
+/// Passes `byte_stream` through until `deadline`, if there is one, passes or `cancel` fires, then
+/// ends it with an error wrapping [`DownloadError::Timeout`] or [`DownloadError::Cancelled`]. A
+/// read that is waiting on the network is abandoned, not finished.
+pub(crate) fn timeout_or_cancel_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    deadline: Option<Instant>,
+    cancel: CancellationToken,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        loop {
+            let next = tokio::select! {
+                next = byte_stream.next() => Ok(next),
+                // Never polled without a deadline.
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => Err(TimeoutOrCancel::Timeout),
+                _ = cancel.cancelled() => Err(TimeoutOrCancel::Cancel),
+            };
+            match next {
+                Ok(Some(item)) => yield item,
+                Ok(None) => return,
+                Err(e) => {
+                    yield Err(std::io::Error::other(DownloadError::from(e)));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Passes `byte_stream` through, ending it with an error wrapping [`DownloadError::Timeout`] if
+/// it goes `idle` without producing anything, e.g. on a stalled connection.
+pub(crate) fn idle_timeout_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    idle: Duration,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        loop {
+            match tokio::time::timeout(idle, byte_stream.next()).await {
+                Ok(Some(item)) => yield item,
+                Ok(None) => return,
+                Err(_) => {
+                    yield Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        DownloadError::Timeout,
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// How far the body of an upload has gone out, see [`progress_stream`].
+#[derive(Debug)]
+pub(crate) struct BodyProgress {
+    /// When the body last produced anything, or when it was created.
+    last: Instant,
+    /// Whether it has produced all of it.
+    done: bool,
+}
+
+impl BodyProgress {
+    pub(crate) fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            last: Instant::now(),
+            done: false,
+        }))
+    }
+}
+
+/// Passes `byte_stream` through, noting in `progress` when it last produced anything and
+/// when it ended.
+pub(crate) fn progress_stream(
+    byte_stream: impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static,
+    progress: Arc<Mutex<BodyProgress>>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static {
+    async_stream::stream! {
+        let mut byte_stream = std::pin::pin!(byte_stream);
+        while let Some(item) = byte_stream.next().await {
+            progress.lock().unwrap().last = Instant::now();
+            yield item;
+        }
+        let mut progress = progress.lock().unwrap();
+        progress.last = Instant::now();
+        progress.done = true;
+    }
+}
+
+/// Runs `send`, a request with a [`progress_stream`] body, until it finishes or `cancel` fires.
+/// It times out if the body goes `idle` without being taken, if the response takes longer than
+/// `timeout` once the body is out, or at `deadline` if there is one. A slow upload that keeps
+/// going isn't cut off otherwise.
+pub(crate) async fn upload_timeout_or_cancel<T>(
+    send: impl Future<Output = T>,
+    progress: &Mutex<BodyProgress>,
+    idle: Duration,
+    timeout: Duration,
+    deadline: Option<Instant>,
+    cancel: &CancellationToken,
+) -> Result<T, TimeoutOrCancel> {
+    let stalled = async {
+        loop {
+            let limit = {
+                let progress = progress.lock().unwrap();
+                progress.last + if progress.done { timeout } else { idle }
+            };
+            if Instant::now() >= limit {
+                return;
+            }
+            tokio::time::sleep_until(limit).await;
+        }
+    };
+
+    tokio::select! {
+        res = send => Ok(res),
+        _ = stalled => Err(TimeoutOrCancel::Timeout),
+        // Never polled without a deadline.
+        _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+            if deadline.is_some() => Err(TimeoutOrCancel::Timeout),
+        _ = cancel.cancelled() => Err(TimeoutOrCancel::Cancel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download_error(item: Option<std::io::Result<Bytes>>) -> Option<DownloadError> {
+        let err = item?.err()?.into_inner()?;
+        err.downcast::<DownloadError>().ok().map(|e| *e)
+    }
+
+    #[tokio::test]
+    async fn stream_ends_in_cancelled_or_timeout() {
+        let cancel = CancellationToken::new();
+        let far = Instant::now() + Duration::from_secs(3600);
+        let source = futures::stream::iter(vec![Ok(Bytes::from_static(b"head"))])
+            .chain(futures::stream::pending());
+        let mut stream =
+            std::pin::pin!(timeout_or_cancel_stream(source, Some(far), cancel.clone()));
+
+        assert_eq!(
+            Bytes::from_static(b"head"),
+            stream.next().await.unwrap().unwrap()
+        );
+        cancel.cancel();
+        assert!(matches!(
+            download_error(stream.next().await),
+            Some(DownloadError::Cancelled)
+        ));
+        assert!(stream.next().await.is_none());
+
+        let past = Instant::now();
+        let stream = timeout_or_cancel_stream(
+            futures::stream::pending(),
+            Some(past),
+            CancellationToken::new(),
+        );
+        let mut stream = std::pin::pin!(stream);
+        assert!(matches!(
+            download_error(stream.next().await),
+            Some(DownloadError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_without_deadline_only_ends_on_cancel() {
+        let cancel = CancellationToken::new();
+        let source = futures::stream::iter(vec![Ok(Bytes::from_static(b"slow"))])
+            .then(|item| async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                item
+            })
+            .chain(futures::stream::pending());
+        let mut stream = std::pin::pin!(timeout_or_cancel_stream(source, None, cancel.clone()));
+
+        assert_eq!(
+            Bytes::from_static(b"slow"),
+            stream.next().await.unwrap().unwrap()
+        );
+        cancel.cancel();
+        assert!(matches!(
+            download_error(stream.next().await),
+            Some(DownloadError::Cancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_times_out() {
+        let idle = Duration::from_millis(50);
+        let chunks = futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"a")),
+            Ok(Bytes::from_static(b"b")),
+        ]);
+        let done: Vec<_> = idle_timeout_stream(chunks, idle).collect().await;
+        assert_eq!(2, done.len());
+
+        let source = futures::stream::iter(vec![Ok(Bytes::from_static(b"a"))])
+            .chain(futures::stream::pending());
+        let mut stream = std::pin::pin!(idle_timeout_stream(source, idle));
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(matches!(
+            download_error(stream.next().await),
+            Some(DownloadError::Timeout)
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    /// Takes `body` the way a request sending it would, then answers after `respond`.
+    async fn fake_send(
+        body: impl Stream<Item = std::io::Result<Bytes>>,
+        respond: Duration,
+    ) -> usize {
+        let mut body = std::pin::pin!(body);
+        let mut sent = 0;
+        while let Some(chunk) = body.next().await {
+            sent += chunk.unwrap().len();
+        }
+        tokio::time::sleep(respond).await;
+        sent
+    }
+
+    #[tokio::test]
+    async fn upload_times_out_only_when_stalled() {
+        let idle = Duration::from_millis(100);
+        let timeout = Duration::from_millis(100);
+        let cancel = CancellationToken::new();
+        let chunks = |n| {
+            futures::stream::iter(vec![Bytes::from_static(b"abcd"); n]).then(|c| async {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                Ok(c)
+            })
+        };
+
+        // Takes several times `idle` and `timeout` in all, but never stalls.
+        let progress = BodyProgress::new();
+        let send = fake_send(progress_stream(chunks(8), progress.clone()), idle / 2);
+        let sent = upload_timeout_or_cancel(send, &progress, idle, timeout, None, &cancel).await;
+        assert_eq!(Ok(32), sent);
+
+        // The body stops coming.
+        let progress = BodyProgress::new();
+        let stalled = chunks(2).chain(futures::stream::pending());
+        let send = fake_send(progress_stream(stalled, progress.clone()), idle / 2);
+        let sent = upload_timeout_or_cancel(send, &progress, idle, timeout, None, &cancel).await;
+        assert_eq!(Err(TimeoutOrCancel::Timeout), sent);
+
+        // The body is out, but no response comes.
+        let progress = BodyProgress::new();
+        let send = fake_send(progress_stream(chunks(2), progress.clone()), timeout * 2);
+        let sent = upload_timeout_or_cancel(send, &progress, idle, timeout, None, &cancel).await;
+        assert_eq!(Err(TimeoutOrCancel::Timeout), sent);
+
+        // An overall deadline cuts off even a steady upload.
+        let progress = BodyProgress::new();
+        let send = fake_send(progress_stream(chunks(8), progress.clone()), idle / 2);
+        let deadline = Some(Instant::now() + Duration::from_millis(150));
+        let sent =
+            upload_timeout_or_cancel(send, &progress, idle, timeout, deadline, &cancel).await;
+        assert_eq!(Err(TimeoutOrCancel::Timeout), sent);
+    }
+}
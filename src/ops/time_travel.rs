@@ -0,0 +1,243 @@
+use crate::ops::gcs_bucket::{CopyOpts, GCSBucket, ListOpts};
+use crate::ops::types::{ListingMode, ListingVersion, TimeTravelError};
+use futures_util::TryStreamExt;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+
+/// One step of putting a key back the way it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// The key was created since: its live generation gets deleted. With versioning on, that
+    /// leaves it as a noncurrent generation.
+    Delete { key: String, generation: u64 },
+    /// The key was deleted or changed since: `generation` gets copied back over it.
+    /// `replacing` is the live generation it takes the place of, if any.
+    Restore {
+        key: String,
+        generation: u64,
+        replacing: Option<u64>,
+    },
+}
+
+/// What [`crate::ops::gcs_bucket::RemoteStorage::time_travel_recover`] does, or would do on a
+/// dry run. Its `Display` is the report, one action a line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryPlan {
+    pub actions: Vec<RecoveryAction>,
+}
+
+impl RecoveryPlan {
+    /// Works out the plan from every generation of the keys concerned: each key ends up with
+    /// the generation that was live at `timestamp`, or with none if none was.
+    pub fn new(versions: Vec<ListingVersion>, timestamp: SystemTime) -> Self {
+        let mut by_key: BTreeMap<String, Vec<ListingVersion>> = BTreeMap::new();
+        for version in versions {
+            by_key.entry(version.key.clone()).or_default().push(version);
+        }
+
+        let actions = by_key
+            .into_iter()
+            .filter_map(|(key, versions)| {
+                let live_now = versions
+                    .iter()
+                    .filter(|v| v.is_live())
+                    .map(|v| v.generation)
+                    .max();
+                let live_then = versions
+                    .iter()
+                    .filter(|v| {
+                        v.time_created <= timestamp
+                            && v.time_deleted.is_none_or(|deleted| deleted > timestamp)
+                    })
+                    .map(|v| v.generation)
+                    .max();
+                match (live_then, live_now) {
+                    (None, None) => None,
+                    (Some(then), Some(now)) if then == now => None,
+                    (None, Some(generation)) => Some(RecoveryAction::Delete { key, generation }),
+                    (Some(generation), replacing) => Some(RecoveryAction::Restore {
+                        key,
+                        generation,
+                        replacing,
+                    }),
+                }
+            })
+            .collect();
+        Self { actions }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+impl std::fmt::Display for RecoveryPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.actions.is_empty() {
+            return writeln!(f, "nothing to recover");
+        }
+        for action in &self.actions {
+            match action {
+                RecoveryAction::Delete { key, generation } => {
+                    writeln!(f, "delete {} (generation {})", key, generation)?
+                }
+                RecoveryAction::Restore {
+                    key,
+                    generation,
+                    replacing: Some(replacing),
+                } => writeln!(
+                    f,
+                    "restore {} to generation {} (replacing generation {})",
+                    key, generation, replacing
+                )?,
+                RecoveryAction::Restore {
+                    key,
+                    generation,
+                    replacing: None,
+                } => writeln!(f, "restore {} to generation {}", key, generation)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GCSBucket {
+    /// Lists every generation under `prefix` and works out how to get it back to `timestamp`.
+    /// Nothing is changed.
+    pub async fn time_travel_plan(
+        &self,
+        prefix: Option<String>,
+        timestamp: SystemTime,
+        cancel: &CancellationToken,
+    ) -> Result<RecoveryPlan, TimeTravelError> {
+        let listings: Vec<_> = self
+            .list_versions_streaming(
+                prefix,
+                ListingMode::NoDelimiter,
+                None,
+                &ListOpts::default(),
+                cancel,
+            )
+            .try_collect()
+            .await?;
+        let versions = listings.into_iter().flat_map(|l| l.versions).collect();
+        Ok(RecoveryPlan::new(versions, timestamp))
+    }
+
+    /// Carries out `plan`: the deletes as one [`GCSBucket::delete_generations`], then the
+    /// restores one at a time.
+    ///
+    /// Every step only goes through if the key is still at the generation the plan saw, so a
+    /// write made since the plan was worked out isn't lost: the recovery stops with an error
+    /// instead. Keys that couldn't be deleted, that way or otherwise, come back in
+    /// [`TimeTravelError::DeleteFailed`] before any restore is tried.
+    pub async fn apply_recovery(
+        &self,
+        plan: &RecoveryPlan,
+        cancel: &CancellationToken,
+    ) -> Result<(), TimeTravelError> {
+        let deletes: Vec<(&str, u64)> = plan
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                RecoveryAction::Delete { key, generation } => Some((key.as_str(), *generation)),
+                RecoveryAction::Restore { .. } => None,
+            })
+            .collect();
+        if !deletes.is_empty() {
+            self.delete_generations(&deletes, cancel).await?;
+        }
+
+        for action in &plan.actions {
+            let RecoveryAction::Restore {
+                key,
+                generation,
+                replacing,
+            } = action
+            else {
+                continue;
+            };
+            let opts = CopyOpts {
+                source_generation: Some(*generation),
+                // 0: only if the key still doesn't exist.
+                if_generation_match: Some(replacing.unwrap_or(0)),
+                ..Default::default()
+            };
+            self.rewrite(key, key, &opts, |_| {}, cancel).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn version(key: &str, generation: u64, created: u64, deleted: Option<u64>) -> ListingVersion {
+        ListingVersion {
+            key: key.to_string(),
+            generation,
+            metageneration: 1,
+            time_created: at(created),
+            last_modified: at(created),
+            time_deleted: deleted.map(at),
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn plan_puts_each_key_back_to_its_generation_at_the_time() {
+        let versions = vec![
+            // Overwritten since.
+            version("changed", 1, 10, Some(30)),
+            version("changed", 2, 30, None),
+            // Created since.
+            version("created", 5, 40, None),
+            // Deleted since.
+            version("deleted", 1, 10, Some(30)),
+            // Untouched since.
+            version("kept", 1, 10, None),
+            // Came and went since.
+            version("passing", 1, 40, Some(50)),
+            // Deleted exactly at the time.
+            version("gone", 1, 10, Some(20)),
+        ];
+        let plan = RecoveryPlan::new(versions, at(20));
+
+        assert_eq!(
+            vec![
+                RecoveryAction::Restore {
+                    key: "changed".to_string(),
+                    generation: 1,
+                    replacing: Some(2),
+                },
+                RecoveryAction::Delete {
+                    key: "created".to_string(),
+                    generation: 5,
+                },
+                RecoveryAction::Restore {
+                    key: "deleted".to_string(),
+                    generation: 1,
+                    replacing: None,
+                },
+            ],
+            plan.actions
+        );
+        assert_eq!(
+            "restore changed to generation 1 (replacing generation 2)\n\
+             delete created (generation 5)\n\
+             restore deleted to generation 1\n",
+            plan.to_string()
+        );
+        assert_eq!(
+            "nothing to recover\n",
+            RecoveryPlan::new(Vec::new(), at(20)).to_string()
+        );
+    }
+}
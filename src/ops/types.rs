@@ -8,38 +8,118 @@ pub struct GCSListResponse {
     #[serde(rename = "nextPageToken")]
     pub next_page_token: Option<String>,
     pub items: Option<Vec<GCSObject>>,
+    /// With a `delimiter`, the "directories" directly under the prefix, each ending in it.
+    pub prefixes: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// An object resource. Everything but `name` may be missing: listings only ask for the fields
+/// they use.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub struct GCSObject {
     pub name: String,
-    pub bucket: String,
-    pub generation: String,
-    pub metageneration: String,
+    pub bucket: Option<String>,
+    pub generation: Option<String>,
+    pub metageneration: Option<String>,
     #[serde(rename = "contentType")]
-    pub content_type: String,
+    pub content_type: Option<String>,
+    #[serde(rename = "cacheControl")]
+    pub cache_control: Option<String>,
+    #[serde(rename = "contentEncoding")]
+    pub content_encoding: Option<String>,
+    #[serde(rename = "contentDisposition")]
+    pub content_disposition: Option<String>,
+    #[serde(rename = "contentLanguage")]
+    pub content_language: Option<String>,
     #[serde(rename = "storageClass")]
-    pub storage_class: String,
+    pub storage_class: Option<String>,
     pub size: Option<String>,
     #[serde(rename = "md5Hash")]
     pub md5_hash: Option<String>,
-    pub crc32c: String,
-    pub etag: String,
+    pub crc32c: Option<String>,
+    pub etag: Option<String>,
     #[serde(rename = "timeCreated")]
-    pub time_created: String,
+    pub time_created: Option<String>,
     pub updated: Option<String>,
     #[serde(rename = "timeStorageClassUpdated")]
-    pub time_storage_class_updated: String,
+    pub time_storage_class_updated: Option<String>,
     #[serde(rename = "timeFinalized")]
-    pub time_finalized: String,
+    pub time_finalized: Option<String>,
+    /// When this generation stopped being the live one, in a bucket with versioning on.
+    #[serde(rename = "timeDeleted")]
+    pub time_deleted: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// The writable part of an object resource, sent as JSON ahead of the media in a
+/// `uploadType=multipart` upload, or as the destination of a rewrite.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GCSObjectResource {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_disposition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_class: Option<String>,
+    /// Base64 big-endian CRC32C of the media. GCS rejects the upload if it doesn't match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc32c: Option<String>,
+    /// Base64 MD5 of the media. GCS rejects the upload if it doesn't match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5_hash: Option<String>,
+}
+
+/// Answer to one `objects.rewrite` call. Until `done`, `rewrite_token` has to be sent with the
+/// next call to carry on.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GCSRewriteResponse {
+    pub total_bytes_rewritten: String,
+    pub object_size: String,
+    pub done: bool,
+    pub rewrite_token: Option<String>,
+    /// The destination object, once `done`.
+    pub resource: Option<GCSObject>,
+}
+
+/// Body GCS sends back with a failed JSON API request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GCSErrorResponse {
+    pub error: GCSError,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GCSError {
+    pub code: u16,
+    pub message: String,
+    #[serde(default)]
+    pub errors: Vec<GCSErrorDetail>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GCSErrorDetail {
+    pub reason: Option<String>,
+    pub message: Option<String>,
 }
 
 impl GCSListResponse {
     pub fn contents(&self) -> &[GCSObject] {
         self.items.as_deref().unwrap_or_default()
     }
+
+    pub fn common_prefixes(&self) -> &[String] {
+        self.prefixes.as_deref().unwrap_or_default()
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -49,12 +129,46 @@ pub struct ListingObject {
     pub size: u64,
 }
 
+/// One generation of an object, from a listing with `versions=true`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ListingVersion {
+    pub key: String,
+    pub generation: u64,
+    pub metageneration: u64,
+    /// When this generation was written.
+    pub time_created: SystemTime,
+    pub last_modified: SystemTime,
+    /// When it was overwritten or deleted; `None` for the live generation.
+    pub time_deleted: Option<SystemTime>,
+    pub size: u64,
+}
+
+impl ListingVersion {
+    pub fn is_live(&self) -> bool {
+        self.time_deleted.is_none()
+    }
+}
+
+/// Whether a listing stops at `/`: with a delimiter, what's under a deeper "directory" comes
+/// back as one entry in [`Listing::prefixes`] instead of as keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListingMode {
+    WithDelimiter,
+    NoDelimiter,
+}
+
 #[derive(Default)]
 pub struct Listing {
     pub prefixes: Vec<String>,
     pub keys: Vec<ListingObject>,
 }
 
+#[derive(Default)]
+pub struct VersionListing {
+    pub prefixes: Vec<String>,
+    pub versions: Vec<ListingVersion>,
+}
+
 /// Reasons for downloads or listings to fail.
 #[derive(Debug)]
 pub enum DownloadError {
@@ -64,6 +178,10 @@ pub enum DownloadError {
     NotFound,
     /// The caller provided an ETag, and the file was not modified.
     Unmodified,
+    /// An `ifGenerationMatch`/`ifMetagenerationMatch` precondition did not hold (`412`).
+    PreconditionFailed,
+    /// The requested byte range starts past the end of the object (`416`).
+    RangeNotSatisfiable { object_size: Option<u64> },
     /// A cancellation token aborted the download, typically during
     /// tenant detach or process shutdown.
     Cancelled,
@@ -87,6 +205,13 @@ impl std::fmt::Display for DownloadError {
             }
             DownloadError::NotFound => write!(f, "No file found for the remote object id given"),
             DownloadError::Unmodified => write!(f, "File was not modified"),
+            DownloadError::PreconditionFailed => write!(f, "Download precondition failed"),
+            DownloadError::RangeNotSatisfiable {
+                object_size: Some(size),
+            } => write!(f, "Byte range not satisfiable, object is {size} bytes"),
+            DownloadError::RangeNotSatisfiable { object_size: None } => {
+                write!(f, "Byte range not satisfiable")
+            }
             DownloadError::Cancelled => write!(f, "Cancelled, shutting down"),
             DownloadError::Timeout => write!(f, "timeout"),
             DownloadError::Fatal(why) => write!(f, "Fatal read error: {why}"),
@@ -97,8 +222,304 @@ impl std::fmt::Display for DownloadError {
 
 impl From<anyhow::Error> for DownloadError {
     fn from(error: anyhow::Error) -> Self {
-        DownloadError::Other(error)
+        match error.downcast_ref::<TimeoutOrCancel>() {
+            Some(TimeoutOrCancel::Timeout) => DownloadError::Timeout,
+            Some(TimeoutOrCancel::Cancel) => DownloadError::Cancelled,
+            None => DownloadError::Other(error),
+        }
+    }
+}
+
+impl From<TimeoutOrCancel> for DownloadError {
+    fn from(error: TimeoutOrCancel) -> Self {
+        match error {
+            TimeoutOrCancel::Timeout => DownloadError::Timeout,
+            TimeoutOrCancel::Cancel => DownloadError::Cancelled,
+        }
     }
 }
 
 impl std::error::Error for DownloadError {}
+
+/// Reasons for uploads to fail.
+#[derive(Debug)]
+pub enum UploadError {
+    /// An `ifGenerationMatch`-style precondition did not hold (`412`).
+    PreconditionFailed,
+    /// The credentials are not allowed to write the object (`401`/`403`).
+    PermissionDenied(String),
+    /// GCS rejected the request itself (`400`), e.g. an invalid object name.
+    BadRequest(String),
+    /// Rate limits or quota were hit (`429`, or a `403` with a rate/quota reason). Worth
+    /// retrying later.
+    QuotaExceeded(String),
+    /// The stream produced a different number of bytes than the size the upload was started
+    /// with. When it produced too many, `actual` is where it was cut off.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// GCS rejected the body as corrupt (a sent checksum didn't match), or what it stored
+    /// doesn't hash to what was streamed.
+    ChecksumMismatch(String),
+    /// A cancellation token aborted the upload.
+    Cancelled,
+    /// The upload took longer than the bucket's timeout.
+    Timeout,
+    /// No response came back: connection, TLS, or reading the source stream failed.
+    Transport(anyhow::Error),
+    /// GCS answered, but not with anything above.
+    Other(anyhow::Error),
+}
+
+impl UploadError {
+    /// Sorts a failed response into a variant, using the `reason` of the JSON error body when
+    /// the status code alone is ambiguous.
+    pub fn from_response(status: http::StatusCode, body: &str) -> Self {
+        let parsed = serde_json::from_str::<GCSErrorResponse>(body).ok();
+        let message = parsed
+            .as_ref()
+            .map(|e| e.error.message.clone())
+            .unwrap_or_else(|| body.to_string());
+        let rate_limited = parsed.as_ref().is_some_and(|e| {
+            e.error.errors.iter().any(|d| {
+                d.reason.as_deref().is_some_and(|r| {
+                    r == "rateLimitExceeded" || r == "userRateLimitExceeded" || r == "quotaExceeded"
+                })
+            })
+        });
+
+        match status {
+            // e.g. `Provided CRC32C "..." doesn't match calculated CRC32C "..."`
+            http::StatusCode::BAD_REQUEST if message.contains("doesn't match calculated") => {
+                UploadError::ChecksumMismatch(message)
+            }
+            http::StatusCode::PRECONDITION_FAILED => UploadError::PreconditionFailed,
+            http::StatusCode::TOO_MANY_REQUESTS => UploadError::QuotaExceeded(message),
+            http::StatusCode::FORBIDDEN if rate_limited => UploadError::QuotaExceeded(message),
+            http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN => {
+                UploadError::PermissionDenied(message)
+            }
+            http::StatusCode::BAD_REQUEST => UploadError::BadRequest(message),
+            _ => UploadError::Other(anyhow::anyhow!(
+                "GCS upload returned {}: {}",
+                status,
+                message
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::PreconditionFailed => write!(f, "Upload precondition failed"),
+            UploadError::PermissionDenied(why) => write!(f, "Permission denied: {why}"),
+            UploadError::BadRequest(why) => write!(f, "Bad upload request: {why}"),
+            UploadError::QuotaExceeded(why) => write!(f, "Rate or quota limit hit: {why}"),
+            UploadError::SizeMismatch { expected, actual } if actual > expected => write!(
+                f,
+                "Upload stream produced more than the {expected} bytes it was sized at"
+            ),
+            UploadError::SizeMismatch { expected, actual } => {
+                write!(f, "Upload stream ended after {actual} of {expected} bytes")
+            }
+            UploadError::ChecksumMismatch(why) => write!(f, "Checksum mismatch: {why}"),
+            UploadError::Cancelled => write!(f, "Cancelled, shutting down"),
+            UploadError::Timeout => write!(f, "timeout"),
+            UploadError::Transport(e) => write!(f, "Failed to send upload: {e:?}"),
+            UploadError::Other(e) => write!(f, "Failed to upload a file: {e:?}"),
+        }
+    }
+}
+
+impl From<anyhow::Error> for UploadError {
+    fn from(error: anyhow::Error) -> Self {
+        UploadError::Other(error)
+    }
+}
+
+impl From<TimeoutOrCancel> for UploadError {
+    fn from(error: TimeoutOrCancel) -> Self {
+        match error {
+            TimeoutOrCancel::Timeout => UploadError::Timeout,
+            TimeoutOrCancel::Cancel => UploadError::Cancelled,
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// What became of a path given to `delete_objects`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    Deleted,
+    /// It wasn't there (`404`), which is as good as deleted.
+    NotFound,
+}
+
+/// A path `delete_objects` couldn't delete.
+#[derive(Debug)]
+pub struct DeleteFailure {
+    pub key: String,
+    /// Status of the last sub-request for it, if it got that far.
+    pub status: Option<http::StatusCode>,
+    pub message: String,
+}
+
+/// Reasons for `delete_objects` to fail.
+#[derive(Debug)]
+pub enum DeleteError {
+    /// These paths couldn't be deleted, even after retries. Every other one was.
+    Failed(Vec<DeleteFailure>),
+    /// A cancellation token aborted the deletion. Some paths may be deleted already.
+    Cancelled,
+}
+
+impl std::fmt::Display for DeleteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteError::Failed(failed) => {
+                write!(f, "Failed to delete {} object(s):", failed.len())?;
+                for failure in failed {
+                    write!(f, " {} ({});", failure.key, failure.message)?;
+                }
+                Ok(())
+            }
+            DeleteError::Cancelled => write!(f, "Cancelled, shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for DeleteError {}
+
+/// Reasons for a point-in-time recovery to fail.
+#[derive(Debug)]
+pub enum TimeTravelError {
+    /// A cancellation token aborted the recovery. Part of it may have been carried out.
+    Cancelled,
+    /// These keys weren't deleted, e.g. as they were written since the plan was worked out
+    /// (`412`). None of the restores were carried out.
+    DeleteFailed(Vec<DeleteFailure>),
+    /// Listing or carrying out the recovery failed. Part of it may have been carried out.
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for TimeTravelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeTravelError::Cancelled => write!(f, "Cancelled, shutting down"),
+            TimeTravelError::DeleteFailed(failed) => {
+                write!(
+                    f,
+                    "Time travel recovery failed to delete {} object(s):",
+                    failed.len()
+                )?;
+                for failure in failed {
+                    write!(f, " {} ({});", failure.key, failure.message)?;
+                }
+                Ok(())
+            }
+            TimeTravelError::Other(e) => write!(f, "Failed to time travel recover a prefix: {e:?}"),
+        }
+    }
+}
+
+impl From<DownloadError> for TimeTravelError {
+    fn from(error: DownloadError) -> Self {
+        match error {
+            DownloadError::Cancelled => TimeTravelError::Cancelled,
+            error => TimeTravelError::Other(error.into()),
+        }
+    }
+}
+
+impl From<DeleteError> for TimeTravelError {
+    fn from(error: DeleteError) -> Self {
+        match error {
+            DeleteError::Cancelled => TimeTravelError::Cancelled,
+            DeleteError::Failed(failed) => TimeTravelError::DeleteFailed(failed),
+        }
+    }
+}
+
+impl From<anyhow::Error> for TimeTravelError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<TimeoutOrCancel>() {
+            Some(TimeoutOrCancel::Cancel) => TimeTravelError::Cancelled,
+            _ => TimeTravelError::Other(error),
+        }
+    }
+}
+
+impl std::error::Error for TimeTravelError {}
+
+/// Why an operation was cut short. Carried inside `anyhow::Error` by the operations that
+/// return one, and turned into the matching [`DownloadError`] or [`UploadError`] variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutOrCancel {
+    Timeout,
+    Cancel,
+}
+
+impl std::fmt::Display for TimeoutOrCancel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeoutOrCancel::Timeout => write!(f, "timeout"),
+            TimeoutOrCancel::Cancel => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for TimeoutOrCancel {}
+
+/// A batch request as a whole got a response other than `200`. Carried inside the
+/// `anyhow::Error` of `send_batch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchStatusError(pub http::StatusCode);
+
+impl std::fmt::Display for BatchStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "batch request returned {}", self.0)
+    }
+}
+
+impl std::error::Error for BatchStatusError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn upload_errors_follow_status_and_reason() {
+        let rate_limited = r#"{"error":{"code":403,"message":"slow down","errors":[{"reason":"userRateLimitExceeded","message":"slow down"}]}}"#;
+        let forbidden =
+            r#"{"error":{"code":403,"message":"no access","errors":[{"reason":"forbidden"}]}}"#;
+
+        assert!(matches!(
+            UploadError::from_response(StatusCode::FORBIDDEN, rate_limited),
+            UploadError::QuotaExceeded(m) if m == "slow down"
+        ));
+        assert!(matches!(
+            UploadError::from_response(StatusCode::FORBIDDEN, forbidden),
+            UploadError::PermissionDenied(m) if m == "no access"
+        ));
+        assert!(matches!(
+            UploadError::from_response(StatusCode::PRECONDITION_FAILED, ""),
+            UploadError::PreconditionFailed
+        ));
+        assert!(matches!(
+            UploadError::from_response(StatusCode::BAD_REQUEST, "not json"),
+            UploadError::BadRequest(m) if m == "not json"
+        ));
+        assert!(matches!(
+            UploadError::from_response(
+                StatusCode::BAD_REQUEST,
+                r#"{"error":{"code":400,"message":"Provided CRC32C \"AAAAAA==\" doesn't match calculated CRC32C \"yZRlqg==\"."}}"#
+            ),
+            UploadError::ChecksumMismatch(_)
+        ));
+        assert!(matches!(
+            UploadError::from_response(StatusCode::INTERNAL_SERVER_ERROR, ""),
+            UploadError::Other(_)
+        ));
+    }
+}